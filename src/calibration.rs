@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::f64::consts::TAU;
+use ndarray::prelude::*;
+use crate::input;
+
+
+
+// Conversion of residual phase in radians to physical height.
+pub enum Calibration {
+    // Height per radian
+    Linear(f64),
+    Triangulation(Triangulation),
+    // Coefficients c[k, row, column] of h = Σ c_k φ^k at each pixel
+    Polynomial(Array3<f64>)
+}
+
+// Geometry of a crossed-optical-axes fringe projection rig, given on the
+// command line as PERIOD,BASELINE,STANDOFF. The period is that of the fringes
+// on the reference plane, the baseline is the distance between the projector
+// and camera pupils, and the standoff is the distance from them to the
+// reference plane, all in the units of height.
+#[derive(Clone, Copy)]
+pub struct Triangulation {
+    pub period: f64,
+    pub baseline: f64,
+    pub standoff: f64
+}
+
+
+
+impl std::str::FromStr for Triangulation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+
+        if parts.len() == 3 {
+            let period: f64 = parts[0].parse().map_err(|_| "Invalid float for fringe period")?;
+            let baseline: f64 = parts[1].parse().map_err(|_| "Invalid float for baseline")?;
+            let standoff: f64 = parts[2].parse().map_err(|_| "Invalid float for standoff")?;
+
+            if period > 0. && baseline > 0. && standoff > 0. {
+                Ok(Self { period, baseline, standoff })
+            }
+            else {
+                Err("Fringe period, baseline and standoff must be positive".to_string())
+            }
+        }
+        else {
+            Err("Triangulation must be of the form PERIOD,BASELINE,STANDOFF".to_string())
+        }
+    }
+}
+
+impl Calibration {
+    // Read per-pixel polynomial coefficients of any numeric type from a .npy
+    // file of shape (terms, height, width), or the array named `array` in an
+    // .npz archive, checking they cover a phase map of size `dim`.
+    pub fn from_polynomial(path: &Path, array: &str, dim: (usize, usize)) -> anyhow::Result<Self> {
+        let coeffs = input::read_array(path, array)?;
+        let shape = coeffs.shape().to_vec();
+        let coeffs: Array3<f64> = coeffs.into_dimensionality()
+            .map_err(|_| anyhow::anyhow!("Calibration {} must be a 3-D array, not one of shape {shape:?}", path.display()))?;
+        let (terms, h, w) = coeffs.dim();
+
+        anyhow::ensure!(
+            terms > 0 && (h, w) == dim,
+            "Calibration {} has shape {:?}, but must be (terms, {}, {}) to match the phase",
+            path.display(), coeffs.shape(), dim.0, dim.1
+        );
+
+        Ok(Self::Polynomial(coeffs))
+    }
+
+    // Height of the residual phase `phase` at pixel (x, y).
+    pub fn height(&self, phase: f64, x: f64, y: f64) -> f64 {
+        match self {
+            &Self::Linear(scale) => phase*scale,
+            Self::Triangulation(t) => {
+                // h = L Δφ / (Δφ + 2π d / p), from similar triangles between
+                // the rig and the shift of the fringes on the reference plane
+                t.standoff*phase/(phase+TAU*t.baseline/t.period)
+            },
+            Self::Polynomial(coeffs) => {
+                let c = coeffs.slice(s![.., y as usize, x as usize]);
+
+                c.iter().rev().fold(0., |h, &c| h*phase+c)
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// Iterations used to invert the distortion model, which converges quickly
+// for the small distortions of measurement lenses.
+const UNDISTORT_ITERATIONS: usize = 20;
+
+// A pinhole camera with radial lens distortion, looking at a plane at the
+// working distance. Lengths are in millimetres and the principal point is in
+// pixels.
+pub struct Camera {
+    pub focal_length: f64,
+    pub principal_point: (f64, f64),
+    pub pixel_pitch: f64,
+    // Coefficients k1 and k2 of the Brown-Conrady model, which distorts
+    // normalised image coordinates by a factor of 1 + k1 r² + k2 r⁴
+    pub distortion: (f64, f64),
+    pub working_distance: f64
+}
+
+// Principal point given on the command line as CX,CY.
+#[derive(Clone, Copy)]
+pub struct PrincipalPoint(pub f64, pub f64);
+
+// Radial distortion given on the command line as K1 or K1,K2.
+#[derive(Clone, Copy)]
+pub struct Distortion(pub f64, pub f64);
+
+
+
+impl std::str::FromStr for PrincipalPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+
+        if parts.len() == 2 {
+            let cx: f64 = parts[0].parse().map_err(|_| "Invalid float for principal point x")?;
+            let cy: f64 = parts[1].parse().map_err(|_| "Invalid float for principal point y")?;
+
+            Ok(Self(cx, cy))
+        }
+        else {
+            Err("Principal point must be of the form CX,CY".to_string())
+        }
+    }
+}
+
+impl std::str::FromStr for Distortion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+
+        if parts.len() <= 2 {
+            let k1: f64 = parts[0].parse().map_err(|_| "Invalid float for distortion k1")?;
+            let k2: f64 = match parts.get(1) {
+                Some(k2) => k2.parse().map_err(|_| "Invalid float for distortion k2")?,
+                None => 0.
+            };
+
+            Ok(Self(k1, k2))
+        }
+        else {
+            Err("Distortion must be of the form K1 or K1,K2".to_string())
+        }
+    }
+}
+
+impl Camera {
+    // Lateral position in millimetres on the plane at the working distance
+    // seen by pixel (x, y), with lens distortion removed. The axes keep the
+    // orientation of the image, with the origin on the optical axis.
+    pub fn lateral(&self, x: f64, y: f64) -> (f64, f64) {
+        let scale = self.pixel_pitch/self.focal_length;
+        let xd = (x-self.principal_point.0)*scale;
+        let yd = (y-self.principal_point.1)*scale;
+        let (k1, k2) = self.distortion;
+
+        // Invert the distortion by fixed point iteration on the undistorted
+        // coordinates
+        let (mut xu, mut yu) = (xd, yd);
+
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let r2 = xu*xu+yu*yu;
+            let factor = 1.+k1*r2+k2*r2*r2;
+
+            (xu, yu) = (xd/factor, yd/factor);
+        }
+
+        (xu*self.working_distance, yu*self.working_distance)
+    }
+}
@@ -0,0 +1,474 @@
+use ndarray::prelude::*;
+use ndarray::Zip;
+use ndarray_linalg::{Inverse, LeastSquaresSvd, Solve};
+use rand::prelude::*;
+use rand::seq::index;
+use anyhow::bail;
+use crate::model::SurfaceModel;
+
+
+
+// Loss functions for iteratively reweighted least squares, each with the
+// tuning constant giving 95% efficiency on normally distributed residuals.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Loss {
+    /// Quadratic near zero, linear in the tails (k = 1.345)
+    Huber,
+    /// Logarithmic growth, so large residuals have little influence (k = 2.385)
+    Cauchy,
+    /// Tukey's biweight, which ignores residuals beyond k entirely (k = 4.685)
+    Tukey
+}
+
+impl Loss {
+    // IRLS weight for a residual `u` already divided by the scale.
+    fn weight(self, u: f64) -> f64 {
+        let u = u.abs();
+
+        match self {
+            Self::Huber => if u <= 1.345 { 1. } else { 1.345/u },
+            Self::Cauchy => 1./(1.+(u/2.385).powi(2)),
+            Self::Tukey => if u < 4.685 { (1.-(u/4.685).powi(2)).powi(2) } else { 0. }
+        }
+    }
+}
+
+// Condition number of the normalised problem above which the fit should not
+// be trusted, e.g. because the points are nearly collinear.
+pub const POOR_CONDITION: f64 = 1e6;
+
+// Result of a RANSAC fit. `inliers` flags the input points within tolerance of
+// the final (refit) surface, and `inlier_fraction` is the fraction of them.
+pub struct RansacFit {
+    pub coeffs: Array1<f64>,
+    pub inliers: Array1<bool>,
+    pub inlier_fraction: f64
+}
+
+// Result of an IRLS fit, with the number of reweighting iterations performed
+// and whether the coefficients settled before the iteration limit. `weights`
+// are the final per-point weights, including any supplied to the fit.
+pub struct IrlsFit {
+    pub coeffs: Array1<f64>,
+    pub weights: Array1<f64>,
+    pub iterations: usize,
+    pub converged: bool
+}
+
+// Result of a sigma-clipped fit. `kept` flags the points that survived the
+// final round of clipping.
+pub struct ClippedFit {
+    pub coeffs: Array1<f64>,
+    pub kept: Array1<bool>,
+    pub iterations: usize,
+    pub converged: bool
+}
+
+// Result of Levenberg-Marquardt refinement, with the weighted RMS residual
+// before and after.
+pub struct RefinedFit {
+    pub coeffs: Array1<f64>,
+    pub iterations: usize,
+    pub converged: bool,
+    pub initial_rms: f64,
+    pub final_rms: f64
+}
+
+// Uncertainty of a set of fit coefficients. The covariance is the residual
+// variance times the inverse of the normal matrix of the model's Jacobian at
+// the solution, and the condition number is that of the linear problem solved
+// by the model's `fit`.
+pub struct FitStatistics {
+    pub covariance: Array2<f64>,
+    pub std_errors: Array1<f64>,
+    pub correlation: Array2<f64>,
+    pub rms: f64,
+    pub peak_to_valley: f64,
+    pub condition_number: f64,
+    pub dof: usize
+}
+
+
+
+// Robust fit using RANSAC. Minimal subsets of as many points as the model has
+// coefficients are fit exactly, and the subset whose surface has the most
+// points within `tolerance` of it wins. The winning consensus set is then
+// refit by ordinary least squares, and the inliers recomputed against that
+// refit. The number of iterations adapts to the best inlier fraction found so
+// far, so that an all-inlier sample has been drawn with 99% confidence, but
+// never exceeds `max_iterations`. `weights` are only used in the final refit.
+pub fn ransac_fit<R: Rng>(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    weights: ArrayView1<f64>,
+    tolerance: f64,
+    max_iterations: usize,
+    rng: &mut R
+) -> anyhow::Result<RansacFit> {
+    let n = points.nrows();
+    let minimal = model.param_count();
+
+    if n < minimal {
+        bail!("RANSAC needs at least {minimal} points, but only {n} passed the threshold");
+    }
+
+    let mut best_count = 0;
+    let mut best_coeffs = None;
+    let mut iterations = max_iterations;
+    let mut i = 0;
+
+    while i < iterations {
+        i += 1;
+
+        let sample = index::sample(rng, n, minimal).into_vec();
+        let coeffs = model.fit(points.select(Axis(0), &sample).view(), Array1::ones(minimal).view());
+
+        if coeffs.iter().any(|c| !c.is_finite()) {
+            continue;
+        }
+
+        let count = find_inliers(model, points, coeffs.view(), tolerance).iter().filter(|&&b| b).count();
+
+        if count > best_count {
+            best_count = count;
+            best_coeffs = Some(coeffs);
+
+            let w = best_count as f64/n as f64;
+            let needed = (0.01f64).ln()/(1.-w.powi(minimal as i32)).ln();
+
+            if needed.is_finite() {
+                iterations = iterations.min(needed.ceil() as usize);
+            }
+        }
+    }
+
+    let Some(best_coeffs) = best_coeffs else {
+        bail!("RANSAC found no valid sample in {max_iterations} iterations");
+    };
+
+    if best_count < minimal {
+        bail!("RANSAC consensus set is too small to refit ({best_count} points)");
+    }
+
+    let inliers = find_inliers(model, points, best_coeffs.view(), tolerance);
+    let indices: Vec<usize> = (0..n).filter(|&i| inliers[i]).collect();
+    let coeffs = model.fit(
+        points.select(Axis(0), &indices).view(),
+        weights.select(Axis(0), &indices).view()
+    );
+    let inliers = find_inliers(model, points, coeffs.view(), tolerance);
+    let inlier_fraction = inliers.iter().filter(|&&b| b).count() as f64/n as f64;
+
+    Ok(RansacFit { coeffs, inliers, inlier_fraction })
+}
+
+fn find_inliers(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    coeffs: ArrayView1<f64>,
+    tolerance: f64
+) -> Array1<bool> {
+    residuals(model, points, coeffs).mapv(|r| r.abs() < tolerance)
+}
+
+// Robust fit using iteratively reweighted least squares.
+// Starting from the weighted least squares solution, each point's weight is
+// multiplied by `loss` applied to its residual divided by the scale, and the
+// weighted fit repeated until the norm of the change in the coefficients is
+// at most 1e-6 of the norm of the coefficients. The scale is `scale` if given,
+// otherwise it is re-estimated every iteration as the normalised median
+// absolute deviation of the residuals.
+pub fn irls_fit(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    weights: ArrayView1<f64>,
+    loss: Loss,
+    scale: Option<f64>,
+    max_iterations: usize
+) -> anyhow::Result<IrlsFit> {
+    let minimal = model.param_count();
+
+    if points.nrows() < minimal {
+        bail!("IRLS needs at least {minimal} points, but only {} passed the threshold", points.nrows());
+    }
+
+    if scale.is_some_and(|s| s.is_nan() || s <= 0.) {
+        bail!("IRLS scale must be positive");
+    }
+
+    let mut coeffs = model.fit(points, weights);
+    let mut robust_weights = weights.to_owned();
+
+    for iterations in 1..=max_iterations {
+        let r = residuals(model, points, coeffs.view());
+        let s = match scale {
+            Some(s) => s,
+            None => mad_scale(&r)
+        };
+
+        if s.is_nan() || s <= 0. {
+            // Every residual is (almost) zero, so there is nothing to reweight
+            return Ok(IrlsFit { coeffs, weights: weights.to_owned(), iterations, converged: true });
+        }
+
+        robust_weights = &r.mapv(|r| loss.weight(r/s))*&weights;
+
+        if robust_weights.iter().filter(|&&w| w > 0.).count() < minimal {
+            bail!("IRLS rejected all but a handful of points; try a larger scale");
+        }
+
+        let new_coeffs = model.fit(points, robust_weights.view());
+
+        // Judged on the whole vector, as coefficients that are really zero
+        // would never settle relative to themselves
+        let change = norm(&(&new_coeffs-&coeffs));
+        let converged = change <= 1e-6*norm(&new_coeffs);
+
+        coeffs = new_coeffs;
+
+        if converged {
+            return Ok(IrlsFit { coeffs, weights: robust_weights, iterations, converged: true });
+        }
+    }
+
+    Ok(IrlsFit { coeffs, weights: robust_weights, iterations: max_iterations, converged: false })
+}
+
+// Fit with iterative sigma clipping. Each round fits to the points kept so
+// far, then keeps only those whose residual is within `n_sigma` times the
+// scale of the kept residuals, until the kept set stops changing or
+// `max_iterations` rounds have been made. Rejected points are reconsidered
+// every round. The scale is the standard deviation, or the normalised median
+// absolute deviation about the median if `use_mad` is set.
+pub fn sigma_clip_fit(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    weights: ArrayView1<f64>,
+    n_sigma: f64,
+    use_mad: bool,
+    max_iterations: usize
+) -> anyhow::Result<ClippedFit> {
+    let minimal = model.param_count();
+    let mut kept = weights.mapv(|w| w > 0.);
+    let mut iterations = 0;
+
+    loop {
+        iterations += 1;
+
+        if kept.iter().filter(|&&k| k).count() < minimal {
+            bail!("Sigma clipping rejected all but a handful of points; try a larger threshold");
+        }
+
+        let coeffs = model.fit(points, (&weights*&kept.mapv(|k| if k { 1. } else { 0. })).view());
+        let r = residuals(model, points, coeffs.view());
+        let kept_r: Array1<f64> = r.iter().zip(&kept).filter(|(_, &k)| k).map(|(&r, _)| r).collect();
+        let (centre, scale) = if use_mad {
+            (median(kept_r.to_vec()), mad_scale(&kept_r))
+        }
+        else {
+            (kept_r.mean().unwrap(), kept_r.std(1.))
+        };
+        let new_kept = Zip::from(&r).and(&weights)
+            .map_collect(|&r, &w| w > 0. && (r-centre).abs() <= n_sigma*scale);
+
+        let converged = new_kept == kept;
+
+        if converged || iterations >= max_iterations {
+            return Ok(ClippedFit { coeffs, kept, iterations, converged });
+        }
+
+        kept = new_kept;
+    }
+}
+
+// Refine coefficients by minimising the weighted sum of squared residuals
+// z - f(x, y) with Levenberg-Marquardt. This matters for models like the
+// rational plane, whose linear fit minimises z(dx+ey+1) - (ax+by+c) instead,
+// which weights each point by its denominator and so biases the fit towards
+// wherever the denominator is largest. Iteration stops once a step reduces the
+// cost by a relative amount below 1e-12, or the damping grows so large that no
+// step can reduce it.
+pub fn refine_fit(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    weights: ArrayView1<f64>,
+    initial: ArrayView1<f64>,
+    max_iterations: usize
+) -> RefinedFit {
+    let cost = |coeffs: ArrayView1<f64>| (residuals(model, points, coeffs).mapv(|r| r*r)*weights).sum();
+    let total_weight = weights.sum();
+    let mut coeffs = initial.to_owned();
+    let mut current = cost(coeffs.view());
+    let initial_rms = (current/total_weight).sqrt();
+    let mut lambda = 1e-3;
+    let mut converged = false;
+    let mut iterations = 0;
+
+    while iterations < max_iterations && !converged {
+        iterations += 1;
+
+        let (jacobian, r) = jacobian(model, points, coeffs.view());
+        let jw = &jacobian*&weights.view().insert_axis(Axis(1));
+        let jtj = jw.t().dot(&jacobian);
+        let jtr = jw.t().dot(&r);
+
+        loop {
+            let mut damped = jtj.clone();
+
+            for i in 0..jtj.nrows() {
+                damped[[i, i]] += lambda*jtj[[i, i]].max(f64::EPSILON);
+            }
+
+            let step = damped.solve(&jtr).ok().filter(|d| d.iter().all(|v| v.is_finite()));
+            let candidate = step.map(|d| &coeffs+&d);
+            let new_cost = candidate.as_ref().map_or(f64::INFINITY, |c| cost(c.view()));
+
+            if new_cost < current {
+                converged = (current-new_cost) < 1e-12*current;
+                coeffs = candidate.unwrap();
+                current = new_cost;
+                lambda = (lambda/10.).max(1e-12);
+                break;
+            }
+
+            lambda *= 10.;
+
+            if lambda > 1e12 {
+                // No step in any direction reduces the cost, so we are at a
+                // minimum
+                converged = true;
+                break;
+            }
+        }
+    }
+
+    RefinedFit {
+        coeffs,
+        iterations,
+        converged,
+        initial_rms,
+        final_rms: (current/total_weight).sqrt()
+    }
+}
+
+// Estimate the uncertainty of `coeffs` fit to `points` with `weights`. Points
+// with zero weight do not count towards the degrees of freedom, nor the peak
+// to valley residual (the form error).
+pub fn fit_statistics(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    weights: ArrayView1<f64>,
+    coeffs: ArrayView1<f64>
+) -> anyhow::Result<FitStatistics> {
+    let (jacobian, r) = jacobian(model, points, coeffs);
+    let n = weights.iter().filter(|&&w| w > 0.).count();
+    let p = model.param_count();
+
+    if n <= p {
+        bail!("Too few points ({n}) to estimate the fit uncertainty");
+    }
+
+    let dof = n-p;
+    let sqrt_w = weights.mapv(f64::sqrt);
+    let jw = &jacobian*&sqrt_w.view().insert_axis(Axis(1));
+    let weighted_ss = (r.mapv(|r| r*r)*weights).sum();
+    let variance = weighted_ss/dof as f64;
+    let covariance = jw.t().dot(&jw).inv()?*variance;
+    let std_errors = covariance.diag().mapv(f64::sqrt);
+    let correlation = Array2::from_shape_fn((p, p), |(i, j)| {
+        covariance[[i, j]]/(std_errors[i]*std_errors[j])
+    });
+    let (rms, peak_to_valley) = residual_spread(&r, weights);
+
+    Ok(FitStatistics {
+        covariance,
+        std_errors,
+        correlation,
+        rms,
+        peak_to_valley,
+        condition_number: model.condition_number(points, weights, coeffs)?,
+        dof
+    })
+}
+
+// Weighted RMS and peak to valley of `residuals`, ignoring those with zero
+// weight.
+pub fn residual_spread(residuals: &Array1<f64>, weights: ArrayView1<f64>) -> (f64, f64) {
+    let weighted_ss = (residuals.mapv(|r| r*r)*weights).sum();
+    let (min_r, max_r) = residuals.iter().zip(&weights)
+        .filter(|(_, &w)| w > 0.)
+        .fold((f64::MAX, f64::MIN), |(lo, hi), (&r, _)| (lo.min(r), hi.max(r)));
+
+    ((weighted_ss/weights.sum()).sqrt(), max_r-min_r)
+}
+
+// Jacobian of the model with respect to its coefficients at each point, along
+// with the residuals there.
+fn jacobian(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    coeffs: ArrayView1<f64>
+) -> (Array2<f64>, Array1<f64>) {
+    let mut jacobian = Array2::<f64>::zeros((points.nrows(), model.param_count()));
+    let mut r = Array1::<f64>::zeros(points.nrows());
+
+    for ((p, mut row), r) in points.rows().into_iter().zip(jacobian.rows_mut()).zip(&mut r) {
+        row.assign(&model.gradient(coeffs, p[0], p[1]));
+        *r = p[2]-model.evaluate(coeffs, p[0], p[1]);
+    }
+
+    (jacobian, r)
+}
+
+// Residuals z - f(x, y) of each point from the surface given by `coeffs`.
+pub fn residuals(model: &dyn SurfaceModel, points: ArrayView2<f64>, coeffs: ArrayView1<f64>) -> Array1<f64> {
+    points.rows().into_iter()
+        .map(|p| p[2]-model.evaluate(coeffs, p[0], p[1]))
+        .collect()
+}
+
+// The piston and tilt part of a surface sampled at each of `points`, i.e. the
+// least squares plane through the samples, evaluated at the same points.
+pub fn plane_component(points: ArrayView2<f64>, surface: ArrayView1<f64>) -> Array1<f64> {
+    let mut matrix = Array2::<f64>::ones((points.nrows(), 3)); // [[x, y, 1], ...]
+
+    matrix.slice_mut(s![.., ..2]).assign(&points.slice(s![.., ..2]));
+
+    let plane = matrix.least_squares(&surface)
+        .expect("Could not find least squares plane for the fitted surface")
+        .solution;
+
+    matrix.dot(&plane)
+}
+
+// Euclidean norm.
+fn norm(v: &Array1<f64>) -> f64 {
+    v.dot(v).sqrt()
+}
+
+// Median absolute deviation about the median, scaled by 1.4826 so that it
+// estimates the standard deviation of normally distributed data.
+pub fn mad_scale(values: &Array1<f64>) -> f64 {
+    let m = median(values.to_vec());
+
+    1.4826*median(values.iter().map(|v| (v-m).abs()).collect())
+}
+
+pub fn median(mut values: Vec<f64>) -> f64 {
+    let n = values.len();
+
+    if n == 0 {
+        return f64::NAN;
+    }
+
+    let (_, &mut upper, _) = values.select_nth_unstable_by(n/2, f64::total_cmp);
+
+    if n%2 == 1 {
+        upper
+    }
+    else {
+        let lower = values[..n/2].iter().copied().fold(f64::MIN, f64::max);
+
+        (lower+upper)/2.
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+use ndarray::prelude::*;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use anyhow::{anyhow, Context};
+use crate::model::{ModelKind, ModelSpec, SurfaceModel};
+use crate::zernike::{Aperture, Indexing};
+use crate::fit::FitStatistics;
+
+
+
+// Everything recorded about a fit when it is saved. `inputs` are the files the
+// fit was made from, each with a short name to record it under. With a
+// `height_unit`, the residual statistics are heights in that unit, and the
+// uncertainties of the coefficients are not saved.
+pub struct FitRecord<'a> {
+    pub spec: &'a ModelSpec,
+    pub model: &'a dyn SurfaceModel,
+    pub method: &'a str,
+    pub coeffs: ArrayView1<'a, f64>,
+    pub stats: Option<&'a FitStatistics>,
+    pub height_unit: Option<&'a str>,
+    pub inputs: Vec<(&'a str, &'a Path)>,
+    pub threshold: f64,
+    pub points: usize
+}
+
+// A fit as written to file. Coefficients and their errors are keyed by name,
+// in the order the model gives them.
+#[derive(Serialize)]
+struct FitFile {
+    created: String,
+    threshold: f64,
+    points: usize,
+    model: Model,
+    inputs: Vec<Input>,
+    coefficients: toml::Table,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    std_errors: Option<toml::Table>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statistics: Option<Statistics>
+}
+
+// The parts of a fit file needed to rebuild the fit. Anything else in it is
+// ignored.
+#[derive(Deserialize)]
+struct SavedFit {
+    model: Model,
+    coefficients: toml::Table
+}
+
+// The model a fit was made with. Only the settings relevant to its kind are
+// written.
+#[derive(Serialize, Deserialize)]
+struct Model {
+    kind: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aperture: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    indexing: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pixel_scale: Option<f64>
+}
+
+#[derive(Serialize)]
+struct Input {
+    name: String,
+    path: String,
+    sha256: String
+}
+
+#[derive(Serialize)]
+struct Statistics {
+    unit: String,
+    residual_rms: f64,
+    peak_to_valley: f64,
+    condition_number: f64,
+    degrees_of_freedom: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    covariance: Option<Vec<Vec<f64>>>,
+    correlation: Vec<Vec<f64>>
+}
+
+
+
+// Save a fit as TOML, with enough information to rebuild the model and to
+// know where the fit came from.
+pub fn save(path: &Path, record: &FitRecord) -> anyhow::Result<()> {
+    let spec = record.spec;
+    let names = record.model.param_names();
+    let by_name = |values: ArrayView1<f64>| -> toml::Table {
+        names.iter().cloned().zip(values.iter().map(|&v| v.into())).collect()
+    };
+    let (order, aperture, indexing, pixel_scale) = match spec.kind {
+        ModelKind::Rational => (None, None, None, None),
+        ModelKind::Polynomial => (Some(spec.order), None, None, None),
+        ModelKind::Zernike => {
+            let Aperture { cx, cy, radius } = spec.aperture.expect("Zernike model saved without an aperture");
+
+            (Some(spec.order), Some([cx, cy, radius]), Some(value_name(spec.indexing)), None)
+        },
+        ModelKind::Sphere | ModelKind::Cylinder => (None, None, None, Some(spec.pixel_scale))
+    };
+    let uncertainties = record.stats.filter(|_| record.height_unit.is_none());
+
+    let file = FitFile {
+        created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        threshold: record.threshold,
+        points: record.points,
+        model: Model {
+            kind: value_name(spec.kind),
+            description: record.model.name(),
+            method: record.method.to_string(),
+            order,
+            aperture,
+            indexing,
+            pixel_scale
+        },
+        inputs: record.inputs.iter()
+            .map(|&(name, input)| Ok(Input {
+                name: name.to_string(),
+                path: input.display().to_string(),
+                sha256: sha256(input)?
+            }))
+            .collect::<anyhow::Result<_>>()?,
+        coefficients: by_name(record.coeffs),
+        std_errors: uncertainties.map(|stats| by_name(stats.std_errors.view())),
+        statistics: record.stats.map(|stats| Statistics {
+            unit: record.height_unit.unwrap_or("rad").to_string(),
+            residual_rms: stats.rms,
+            peak_to_valley: stats.peak_to_valley,
+            condition_number: stats.condition_number,
+            degrees_of_freedom: stats.dof,
+            covariance: uncertainties.map(|stats| rows(&stats.covariance)),
+            correlation: rows(&stats.correlation)
+        })
+    };
+
+    fs::write(path, toml::to_string(&file)?)
+        .with_context(|| format!("Could not write fit file {}", path.display()))
+}
+
+// Load a fit saved by `save`, returning the model it was made with and its
+// coefficients.
+pub fn load(path: &Path) -> anyhow::Result<(ModelSpec, Array1<f64>)> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Could not read fit file {}", path.display()))?;
+    let SavedFit { model, coefficients } = toml::from_str(&text)
+        .with_context(|| format!("Could not parse fit file {}", path.display()))?;
+
+    let kind = ModelKind::from_str(&model.kind, true).map_err(|e| anyhow!(e))?;
+    let indexing = match &model.indexing {
+        Some(name) => Indexing::from_str(name, true).map_err(|e| anyhow!(e))?,
+        None => Indexing::Noll
+    };
+    let aperture = model.aperture.map(|[cx, cy, radius]| Aperture { cx, cy, radius });
+
+    if kind == ModelKind::Zernike && aperture.is_none() {
+        anyhow::bail!("Zernike fit file {} has no aperture", path.display());
+    }
+
+    let spec = ModelSpec {
+        kind,
+        order: model.order.unwrap_or(2),
+        aperture,
+        indexing,
+        pixel_scale: model.pixel_scale.unwrap_or(1.)
+    };
+    let coeffs = spec.build().param_names().iter()
+        .map(|name| {
+            // Hand edited coefficients may have been written as integers
+            coefficients.get(name)
+                .and_then(|v| v.as_float().or(v.as_integer().map(|v| v as f64)))
+                .ok_or_else(|| anyhow!("Fit file {} is missing coefficient {name}", path.display()))
+        })
+        .collect::<anyhow::Result<Array1<f64>>>()?;
+
+    Ok((spec, coeffs))
+}
+
+
+
+fn value_name(v: impl ValueEnum) -> String {
+    v.to_possible_value().unwrap().get_name().to_string()
+}
+
+fn sha256(path: &Path) -> anyhow::Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    Ok(format!("{:x}", Sha256::digest(&bytes)))
+}
+
+fn rows(matrix: &Array2<f64>) -> Vec<Vec<f64>> {
+    matrix.rows().into_iter().map(|r| r.to_vec()).collect()
+}
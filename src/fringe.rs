@@ -0,0 +1,46 @@
+use std::f64::consts::TAU;
+use ndarray::prelude::*;
+use ndarray::Zip;
+
+
+
+// Measures of how reliable the phase of each pixel is, computed from its
+// fringes.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Quality {
+    /// Amplitude of the fringes, in units of intensity
+    Modulation,
+    /// Amplitude of the fringes relative to the mean intensity, which is not
+    /// affected by the reflectivity of the surface
+    Contrast
+}
+
+
+
+// Wrapped phase and quality of N frames with intensity A + B cos(φ + 2πn/N)
+// for n = 0..N, by the N-step arctangent formula.
+pub fn demodulate(stack: ArrayView3<f64>, quality: Quality) -> anyhow::Result<(Array2<f64>, Array2<f64>)> {
+    let n = stack.len_of(Axis(0));
+
+    anyhow::ensure!(n >= 3, "At least 3 phase shifted frames are needed, not {n}");
+
+    let (_, h, w) = stack.dim();
+    let mut sin = Array2::<f64>::zeros((h, w));
+    let mut cos = Array2::<f64>::zeros((h, w));
+
+    for (k, frame) in stack.outer_iter().enumerate() {
+        let delta = TAU*k as f64/n as f64;
+
+        sin.scaled_add(delta.sin(), &frame);
+        cos.scaled_add(delta.cos(), &frame);
+    }
+
+    let wrapped = Zip::from(&sin).and(&cos).map_collect(|&s, &c| (-s).atan2(c));
+    let modulation = Zip::from(&sin).and(&cos).map_collect(|&s, &c| 2.*s.hypot(c)/n as f64);
+    let quality = match quality {
+        Quality::Modulation => modulation,
+        Quality::Contrast => &modulation/&stack.mean_axis(Axis(0)).unwrap()
+    };
+
+    Ok((wrapped, quality))
+}
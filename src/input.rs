@@ -0,0 +1,193 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use ndarray::prelude::*;
+use ndarray_npy::{ReadNpyError, ReadNpyExt, ReadableElement};
+use tiff::decoder::{Decoder, DecodingResult};
+use anyhow::Context;
+
+
+
+type Reader = fn(&[u8]) -> Result<ArrayD<f64>, ReadNpyError>;
+
+// Element types that maps may be stored as, each converted to f64 on reading.
+// Either byte order is accepted, and booleans become 0 or 1.
+const READERS: [Reader; 11] = [
+    |b| read_as::<f64>(b, |&v| v),
+    |b| read_as::<f32>(b, |&v| v as f64),
+    |b| read_as::<i8>(b, |&v| v as f64),
+    |b| read_as::<i16>(b, |&v| v as f64),
+    |b| read_as::<i32>(b, |&v| v as f64),
+    |b| read_as::<i64>(b, |&v| v as f64),
+    |b| read_as::<u8>(b, |&v| v as f64),
+    |b| read_as::<u16>(b, |&v| v as f64),
+    |b| read_as::<u32>(b, |&v| v as f64),
+    |b| read_as::<u64>(b, |&v| v as f64),
+    |b| read_as::<bool>(b, |&v| v as u8 as f64)
+];
+
+
+
+// Read a 2-D map of any real numeric type, choosing the format by extension.
+// Maps can be .npy files, the array named `array` in an .npz archive, or
+// single channel TIFF or PNG images. Anything else is assumed to be .npy.
+pub fn read_map(path: &Path, array: &str) -> anyhow::Result<Array2<f64>> {
+    let map = read_array(path, array)?;
+    let shape = map.shape().to_vec();
+
+    map.into_dimensionality()
+        .map_err(|_| anyhow::anyhow!("{} must hold a 2-D map, not an array of shape {shape:?}", path.display()))
+}
+
+// Read a stack of 2-D maps, indexed by [map, row, column], either as a single
+// 3-D array in a .npy file or .npz archive, or as one map per path.
+pub fn read_stack(paths: &[PathBuf], array: &str) -> anyhow::Result<Array3<f64>> {
+    if let [path] = paths {
+        let stack = read_array(path, array)?;
+        let shape = stack.shape().to_vec();
+
+        return stack.into_dimensionality()
+            .map_err(|_| anyhow::anyhow!("{} must hold a 3-D stack of maps, not an array of shape {shape:?}", path.display()));
+    }
+
+    let maps = paths.iter()
+        .map(|path| read_map(path, array))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    for (path, map) in paths.iter().zip(&maps) {
+        anyhow::ensure!(
+            map.dim() == maps[0].dim(),
+            "{} is {:?}, but {} is {:?}", path.display(), map.dim(), paths[0].display(), maps[0].dim()
+        );
+    }
+
+    let views: Vec<_> = maps.iter().map(|m| m.view()).collect();
+
+    Ok(ndarray::stack(Axis(0), &views)?)
+}
+
+// Resample `map` to size `dim`. Each new pixel is the mean of the old pixels
+// it covers, or the old pixel under its top left corner if it covers none.
+pub fn resample(map: &Array2<f64>, dim: (usize, usize)) -> Array2<f64> {
+    let span = |i: usize, new: usize, old: usize| {
+        let start = i*old/new;
+
+        start..((i+1)*old/new).max(start+1)
+    };
+
+    Array2::from_shape_fn(dim, |(i, j)| {
+        map.slice(s![span(i, dim.0, map.nrows()), span(j, dim.1, map.ncols())]).mean().unwrap()
+    })
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    extension(path).as_deref() == Some("npz")
+}
+
+// Read an array of any dimension from any of the formats `read_map` accepts.
+pub fn read_array(path: &Path, array: &str) -> anyhow::Result<ArrayD<f64>> {
+    let bytes = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let context = || format!("Could not read {}", path.display());
+
+    match extension(path).as_deref() {
+        Some("npz") => {
+            let bytes = archive_entry(&bytes, array)
+                .with_context(|| format!("Could not read array {array} from {}", path.display()))?;
+
+            decode(&bytes, &format!("Array {array} in {}", path.display()))
+        },
+        Some("tif" | "tiff") => Ok(decode_tiff(&bytes).with_context(context)?.into_dyn()),
+        Some("png") => Ok(decode_png(&bytes).with_context(context)?.into_dyn()),
+        _ => decode(&bytes, &path.display().to_string())
+    }
+}
+
+// Decode .npy `bytes`, naming them `name` in errors.
+fn decode(bytes: &[u8], name: &str) -> anyhow::Result<ArrayD<f64>> {
+    let mut descr = String::new();
+
+    for read in READERS {
+        match read(bytes) {
+            Ok(map) => return Ok(map),
+            Err(ReadNpyError::WrongDescriptor(d)) => descr = d.to_string(),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {name}"))
+        }
+    }
+
+    anyhow::bail!("{name} has unsupported dtype {descr}, rather than a boolean, integer or float type")
+}
+
+// Decode a single channel TIFF of any sample type.
+fn decode_tiff(bytes: &[u8]) -> anyhow::Result<Array2<f64>> {
+    let mut decoder = Decoder::new(Cursor::new(bytes))?;
+    let (w, h) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+
+    anyhow::ensure!(matches!(color, tiff::ColorType::Gray(_)), "Only single channel images can be read, not {color:?}");
+
+    let values: Vec<f64> = match decoder.read_image()? {
+        DecodingResult::U8(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::U16(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::U32(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::U64(v) => v.into_iter().map(|v| v as f64).collect(),
+        DecodingResult::I8(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::I16(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::I32(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::I64(v) => v.into_iter().map(|v| v as f64).collect(),
+        DecodingResult::F32(v) => v.into_iter().map(f64::from).collect(),
+        DecodingResult::F64(v) => v
+    };
+
+    Ok(Array2::from_shape_vec((h as usize, w as usize), values)?)
+}
+
+// Decode a greyscale PNG, with depths below 8 bits expanded to 8.
+fn decode_png(bytes: &[u8]) -> anyhow::Result<Array2<f64>> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+
+    decoder.set_transformations(png::Transformations::EXPAND);
+
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let buffer = &buffer[..info.buffer_size()];
+
+    anyhow::ensure!(
+        info.color_type == png::ColorType::Grayscale,
+        "Only greyscale images can be read, not {:?}", info.color_type
+    );
+
+    // 16 bit samples are big-endian
+    let values: Vec<f64> = match info.bit_depth {
+        png::BitDepth::Sixteen => buffer.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as f64).collect(),
+        _ => buffer.iter().map(|&b| b as f64).collect()
+    };
+
+    Ok(Array2::from_shape_vec((info.height as usize, info.width as usize), values)?)
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+}
+
+// The .npy bytes of `array` in an .npz archive. NumPy stores each array as
+// NAME.npy, but bare names are accepted too.
+fn archive_entry(bytes: &[u8], array: &str) -> anyhow::Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let names: Vec<String> = archive.file_names().map(String::from).collect();
+    let name = [format!("{array}.npy"), array.to_string()].into_iter()
+        .find(|n| names.contains(n))
+        .ok_or_else(|| anyhow::anyhow!(
+            "No such array (arrays are {})",
+            names.iter().map(|n| n.trim_end_matches(".npy")).collect::<Vec<_>>().join(", ")
+        ))?;
+    let mut entry = Vec::new();
+
+    archive.by_name(&name)?.read_to_end(&mut entry)?;
+
+    Ok(entry)
+}
+
+fn read_as<T: ReadableElement>(bytes: &[u8], convert: fn(&T) -> f64) -> Result<ArrayD<f64>, ReadNpyError> {
+    ArrayD::<T>::read_npy(bytes).map(|map| map.map(convert))
+}
@@ -1,14 +1,31 @@
-use std::ops::{MulAssign, Range};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::f64::consts::TAU;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{Write, BufWriter};
 use std::process::Command;
 use ndarray::prelude::*;
-use ndarray_npy::ReadNpyExt;
-use ndarray_linalg::LeastSquaresSvd;
-use clap::Parser;
+use ndarray::Zip;
+use ndarray_npy::WriteNpyExt;
+use clap::{Parser, ValueEnum};
+use rand::prelude::*;
 use tempfile::NamedTempFile;
 
+mod calibration;
+mod camera;
+mod fit;
+mod fitfile;
+mod fringe;
+mod input;
+mod model;
+mod primitive;
+mod roi;
+mod temporal;
+mod unwrap;
+mod zernike;
+
+use model::{ModelKind, ModelSpec, SurfaceModel};
+
 
 
 #[derive(Clone, Copy)]
@@ -38,6 +55,44 @@ impl std::fmt::Display for Dimensions {
     }
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum Removal {
+    /// Remove the whole fitted surface
+    Full,
+    /// Remove only the constant term of the fit, and not the mean of what is
+    /// left, so that the offset of the data from the fit is kept
+    Piston,
+    /// Remove the plane through the fit, keeping its curvature and perspective
+    Tilt,
+    /// Remove everything but the plane through the fit, keeping its tilt
+    Perspective
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FitMethod {
+    /// Ordinary least squares over every point above the threshold
+    LeastSquares,
+    /// RANSAC over minimal subsets, refit on the consensus set
+    Ransac,
+    /// Iteratively reweighted least squares with a robust loss
+    Irls,
+    /// Least squares, repeatedly refit after rejecting points with outlying
+    /// residuals
+    SigmaClip
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mismatch {
+    /// Stop with an error
+    Error,
+    /// Crop the phase and quality to the region they have in common, from the
+    /// top left corner
+    Crop,
+    /// Resample the quality to the size of the phase, e.g. when it was
+    /// computed with different binning
+    Resample
+}
+
 #[derive(Parser)]
 /// Produce a nice plot of unwrapped phase data by fitting and removing an
 /// underlying plane.
@@ -45,44 +100,347 @@ impl std::fmt::Display for Dimensions {
 /// Imaging a plane using a double-slit pattern and a perspective camera gives
 /// a phase image of the form f(x,y) = (ax+by+c)/(dx+by+1). First, we perform a
 /// least-squares fit of this equation, then subtract that fit from the data.
-/// The data is shifted by a constant so that its mean is 0 before being plotted.
-/// Plotting is done with gnuplot.
+/// The data is shifted by a constant so that its mean is 0 before being plotted
+/// (or by a multiple of 2π, see --snap-offset). Plotting is done with gnuplot.
+///
+/// Where lens distortion curves the background, a bivariate polynomial can be
+/// fit and removed instead of the plane (see --model). For a circular pupil,
+/// Zernike polynomials can be fit over the aperture and only chosen terms of
+/// them removed (see --remove-terms). Spheres and cylinders can also be fit,
+/// for reference targets of those shapes, in which case the peak to valley
+/// residual of the fit is their form error.
+///
+/// The residual phase can be converted to height with a linear factor, a
+/// triangulation model of the rig or a per-pixel polynomial calibration, in
+/// which case the plot and statistics are in units of height. Given the camera
+/// intrinsics, points are also placed at their lateral position in millimetres,
+/// corrected for lens distortion, before fitting.
 struct Args {
-    /// Input unwrapped phase
-    unwrapped: PathBuf,
+    #[arg(required_unless_present_any = ["fringes", "temporal"])]
+    /// Input unwrapped phase (or wrapped phase with --unwrap), either a .npy
+    /// file, a TIFF or PNG image, or an .npz archive that may also hold the
+    /// quality. Left out with --fringes or --temporal
+    unwrapped: Option<PathBuf>,
 
-    /// Input quality
-    quality: PathBuf,
+    /// Input quality, which can be left out if it is in the unwrapped phase
+    /// archive
+    quality: Option<PathBuf>,
 
     /// Output image
-    output: PathBuf,
+    output: Option<PathBuf>,
+
+    #[arg(long, default_value_t = ("unwrapped").to_string(), value_name = "NAME")]
+    /// Name of the unwrapped phase array in .npz archives
+    phase_array: String,
+
+    #[arg(long, value_enum, value_name = "METHOD")]
+    /// Unwrap the input phase with this method, rather than it being unwrapped
+    /// already. Pixels below the quality threshold or masked out are left out
+    unwrap: Option<unwrap::Method>,
+
+    #[arg(long, value_delimiter = ',', value_name = "FILES", requires = "frequencies", conflicts_with_all = ["fringes", "unwrap"])]
+    /// Unwrap the phase pixel by pixel from wrapped phases at several fringe
+    /// frequencies, given as one 3-D array or as one map per frequency
+    /// separated by commas. The quality is then the consistency between
+    /// frequencies, from 0 to 1
+    temporal: Option<Vec<PathBuf>>,
+
+    #[arg(long, value_delimiter = ',', value_name = "FREQUENCIES", requires = "temporal")]
+    /// Number of fringes across the field in each of the --temporal phases,
+    /// separated by commas
+    frequencies: Option<Vec<f64>>,
+
+    #[arg(long, value_enum, default_value_t = temporal::Scheme::Hierarchical)]
+    /// How the --temporal phases are combined
+    temporal_scheme: temporal::Scheme,
+
+    #[arg(long, value_delimiter = ',', value_name = "FILES")]
+    /// Compute the wrapped phase and quality from N phase shifted fringe
+    /// images, given as one 3-D array of frames or as one image per frame
+    /// separated by commas, and unwrap it (quality guided unless --unwrap is
+    /// given)
+    fringes: Option<Vec<PathBuf>>,
+
+    #[arg(long, default_value_t = ("fringes").to_string(), value_name = "NAME")]
+    /// Name of the fringe array in .npz archives
+    fringe_array: String,
+
+    #[arg(long, value_enum, default_value_t = fringe::Quality::Modulation)]
+    /// Quality computed from the fringes
+    fringe_quality: fringe::Quality,
+
+    #[arg(long, default_value_t = ("wrapped").to_string(), value_name = "NAME")]
+    /// Name of the wrapped phase array in .npz archives, used with --unwrap
+    /// and --temporal
+    wrapped_array: String,
+
+    #[arg(long, value_name = "FILE")]
+    /// Save the unwrapped phase to a .npy file, e.g. to reuse that found by
+    /// --unwrap, --fringes or --temporal, with NaN where it could not be
+    /// unwrapped
+    save_unwrapped: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 1., value_name = "SCALE")]
+    /// Factor the unwrapped and reference phase are multiplied by on reading,
+    /// to convert phase stored as integers in images back to radians
+    phase_scale: f64,
+
+    #[arg(long, default_value_t = 0., value_name = "OFFSET", allow_hyphen_values = true)]
+    /// Offset added to the unwrapped and reference phase on reading, after
+    /// scaling
+    phase_offset: f64,
+
+    #[arg(long, value_enum, default_value_t = Mismatch::Error)]
+    /// What to do when the quality is a different size to the unwrapped phase
+    shape_mismatch: Mismatch,
+
+    #[arg(long, default_value_t = ("quality").to_string(), value_name = "NAME")]
+    /// Name of the quality array in .npz archives
+    quality_array: String,
 
     #[arg(short, long, default_value_t = Dimensions(640, 480))]
     /// Dimensions of the output image
     dimensions: Dimensions,
 
     #[arg(short, long, value_parser = parse_range, allow_hyphen_values = true)]
-    /// Range of values for the z-axis
+    /// Range of values for the z-axis, in units of height if calibrated
     zlim: Option<Range<f64>>,
 
     #[arg(short, long, default_value_t = 0.)]
     /// Quality threshold, below which points are not plotted
     threshold: f64,
 
+    #[arg(long, value_name = "FILE")]
+    /// Unwrapped phase of a flat reference captured with the same rig, which is
+    /// subtracted pixel by pixel before any fitting
+    reference: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE", requires = "reference")]
+    /// Quality of the reference map
+    reference_quality: Option<PathBuf>,
+
+    #[arg(long, value_name = "THRESHOLD", requires = "reference_quality")]
+    /// Reference quality threshold, below which points are not plotted, if
+    /// different to --threshold
+    reference_threshold: Option<f64>,
+
+    #[arg(long, value_name = "FILE")]
+    /// Mask of the pixels to use, as a .npy map, .npz archive or image that is
+    /// nonzero where valid, combined with the quality threshold
+    mask: Option<PathBuf>,
+
+    #[arg(long, default_value_t = ("mask").to_string(), value_name = "NAME")]
+    /// Name of the mask array in .npz archives given to --mask or --roi-mask
+    mask_array: String,
+
+    #[arg(long, value_name = "X0,Y0,X1,Y1", conflicts_with_all = ["roi_polygon", "roi_mask"])]
+    /// Only fit to points within this rectangle of pixels, e.g. a flat fixture
+    /// around the part, but still remove the fit from every point
+    roi_rect: Option<roi::Rect>,
+
+    #[arg(long, value_name = "X1,Y1,X2,Y2,...", conflicts_with = "roi_mask")]
+    /// Only fit to points within this polygon of pixels, with at least 3
+    /// vertices
+    roi_polygon: Option<roi::Polygon>,
+
+    #[arg(long, value_name = "FILE")]
+    /// Only fit to points where this mask is nonzero, given as a .npy map,
+    /// .npz archive or image
+    roi_mask: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Don't fit or remove any surface, e.g. when a reference map has already
+    /// removed the background
+    no_fit: bool,
+
+    #[arg(long)]
+    /// Remove the nearest multiple of 2π to the mean of the residual phase,
+    /// rather than the mean itself, so that colors are comparable between
+    /// captures. This is done before any conversion to height
+    snap_offset: bool,
+
+    #[arg(long, value_name = "SCALE", conflicts_with_all = ["triangulation", "height_calibration"])]
+    /// Convert the residual phase to height by multiplying it by this factor
+    height_scale: Option<f64>,
+
+    #[arg(long, value_name = "PERIOD,BASELINE,STANDOFF", conflicts_with = "height_calibration")]
+    /// Convert the residual phase to height by triangulation, given the fringe
+    /// period on the reference plane, the projector to camera baseline and
+    /// their standoff from the reference plane
+    triangulation: Option<calibration::Triangulation>,
+
+    #[arg(long, value_name = "FILE")]
+    /// Convert the residual phase to height with a polynomial in phase at each
+    /// pixel, its coefficients read from a .npy file or .npz archive holding
+    /// an array of shape (terms, height, width) in increasing order of power
+    height_calibration: Option<PathBuf>,
+
+    #[arg(long, default_value_t = ("calibration").to_string(), value_name = "NAME")]
+    /// Name of the height calibration array in .npz archives
+    calibration_array: String,
+
+    #[arg(long, default_value_t = ("mm").to_string(), value_name = "UNIT")]
+    /// Unit of height given by the calibration, used to label the plot
+    height_unit: String,
+
+    #[arg(long, value_name = "MM", requires_all = ["pixel_pitch", "working_distance"])]
+    /// Focal length of the camera, so that points are placed at their lateral
+    /// position in millimetres rather than in pixels
+    focal_length: Option<f64>,
+
+    #[arg(long, value_name = "CX,CY", requires = "focal_length")]
+    /// Principal point of the camera in pixels, the centre of the image if not
+    /// supplied
+    principal_point: Option<camera::PrincipalPoint>,
+
+    #[arg(long, value_name = "MM", requires = "focal_length")]
+    /// Distance between the centres of adjacent camera pixels
+    pixel_pitch: Option<f64>,
+
+    #[arg(long, value_name = "K1[,K2]", requires = "focal_length")]
+    /// Radial distortion coefficients of the camera lens, corrected for before
+    /// fitting
+    distortion: Option<camera::Distortion>,
+
+    #[arg(long, value_name = "MM", requires = "focal_length")]
+    /// Distance from the camera to the reference plane
+    working_distance: Option<f64>,
+
+    #[arg(long, value_name = "FILE")]
+    /// Save the plotted points to a text file, one 'x y z' line per point
+    save_points: Option<PathBuf>,
+
     #[arg(short, long, default_value_t = 1., value_name = "PERIOD")]
-    /// Period over which the color cycle repeats in the z-direction
+    /// Period over which the color cycle repeats in the z-direction, in units
+    /// of height if calibrated
     color_period: f64,
 
-    #[arg(short, long, num_args = 5, value_name = "COEFFS", allow_hyphen_values = true)]
-    /// The fit coefficients a, b, c, d, and e, which will be generated from the
-    /// data if not supplied (see --help text)
+    #[arg(short, long, value_delimiter = ',', value_name = "C1,C2,...", allow_hyphen_values = true, conflicts_with = "load_fit")]
+    /// The fit coefficients, separated by commas (a,b,c,d,e for the plane),
+    /// which will be generated from the data if not supplied (see --help text)
     fit_coefficients: Option<Vec<f64>>,
 
+    #[arg(long, value_enum, default_value_t = ModelKind::Rational)]
+    /// Surface model to fit and remove
+    model: ModelKind,
+
+    #[arg(long, default_value_t = 2)]
+    /// Order of the polynomial or Zernike model
+    order: u32,
+
+    #[arg(long, default_value_t = 1., value_name = "SCALE")]
+    /// Size of a pixel (or of a millimetre if the camera is given) in the units
+    /// of the phase, so that sphere and cylinder fits are made in consistent
+    /// units
+    pixel_scale: f64,
+
+    #[arg(long, value_name = "CX,CY,RADIUS")]
+    /// Circular aperture for the Zernike model, in pixels or in millimetres if
+    /// the camera is given, estimated from the points above the threshold if
+    /// not supplied
+    aperture: Option<zernike::Aperture>,
+
+    #[arg(long, value_enum, default_value_t = zernike::Indexing::Noll)]
+    /// Numbering of Zernike terms
+    zernike_indexing: zernike::Indexing,
+
+    #[arg(long, value_delimiter = ',', value_name = "NAMES")]
+    /// Only remove these terms of the fit, given by coefficient name and
+    /// separated by commas (e.g. Z1,Z2,Z3 to remove piston and tilt from a
+    /// Zernike fit)
+    remove_terms: Option<Vec<String>>,
+
+    #[arg(long, value_enum, default_value_t = Removal::Full)]
+    /// Which components of the fit to remove from the data, so that e.g. the
+    /// tilt of a specimen can be kept while removing camera perspective
+    remove: Removal,
+
+    #[arg(short, long, value_enum, default_value_t = FitMethod::LeastSquares)]
+    /// Method used to fit the plane when coefficients are not supplied
+    method: FitMethod,
+
+    #[arg(long, default_value_t = 0.5, value_name = "TOL")]
+    /// Maximum absolute residual for a point to count as a RANSAC inlier
+    ransac_tolerance: f64,
+
+    #[arg(long, default_value_t = 1000, value_name = "N")]
+    /// Maximum number of RANSAC iterations
+    ransac_iterations: usize,
+
+    #[arg(long, value_name = "SEED")]
+    /// Seed for RANSAC sampling, for reproducible fits
+    ransac_seed: Option<u64>,
+
+    #[arg(long, value_enum, default_value_t = fit::Loss::Huber)]
+    /// Loss function used by IRLS
+    loss: fit::Loss,
+
+    #[arg(long, value_name = "SCALE")]
+    /// Residual scale used by IRLS, estimated from the median absolute
+    /// deviation of the residuals at each iteration if not supplied
+    irls_scale: Option<f64>,
+
+    #[arg(long, default_value_t = 50, value_name = "N")]
+    /// Maximum number of IRLS iterations
+    irls_iterations: usize,
+
+    #[arg(long, default_value_t = 3., value_name = "N")]
+    /// Residuals further than this many standard deviations from their mean
+    /// are rejected by sigma clipping
+    clip_sigma: f64,
+
+    #[arg(long)]
+    /// Measure the spread of residuals for sigma clipping with the median
+    /// absolute deviation rather than the standard deviation
+    clip_mad: bool,
+
+    #[arg(long, default_value_t = 10, value_name = "N")]
+    /// Maximum number of sigma clipping iterations
+    clip_iterations: usize,
+
+    #[arg(long)]
+    /// Leave points rejected as outliers by the fit out of the plot, as they
+    /// are usually unwrapping failures
+    exclude_outliers: bool,
+
+    #[arg(short = 'w', long, value_parser = parse_quality_weight, value_name = "WEIGHTING")]
+    /// Weight each point in the fit by its quality raised to a power, given
+    /// either as 'linear', 'squared' or a numeric exponent
+    quality_weight: Option<f64>,
+
+    #[arg(long)]
+    /// Refine the fit with Levenberg-Marquardt to minimise the true residual
+    /// rather than the linearised one
+    refine: bool,
+
+    #[arg(long, default_value_t = 100, value_name = "N")]
+    /// Maximum number of Levenberg-Marquardt iterations
+    refine_iterations: usize,
+
+    #[arg(long, value_name = "FILE", visible_alias = "fit-report", conflicts_with_all = ["fit_coefficients", "load_fit", "no_fit"])]
+    /// Save the fit to a TOML file, recording the model, coefficients and their
+    /// uncertainties, residual statistics, threshold, input file hashes and date
+    save_fit: Option<PathBuf>,
+
+    #[arg(long, value_name = "FILE")]
+    /// Load the model and coefficients from a file written by --save-fit
+    /// instead of fitting them
+    load_fit: Option<PathBuf>,
+
     #[arg(long, default_value_t = ("jpeg").to_string())]
     /// Gnuplot backend to use
     backend: String
 }
 
+fn ensure_shape(what: &str, path: &Path, dim: (usize, usize), phase_dim: (usize, usize)) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        dim == phase_dim,
+        "The {what} {} has shape {dim:?}, but the phase has shape {phase_dim:?}", path.display()
+    );
+
+    Ok(())
+}
+
 fn parse_range(s: &str) -> Result<Range<f64>, String> {
     let parts: Vec<&str> = s.split("..").collect();
 
@@ -97,56 +455,392 @@ fn parse_range(s: &str) -> Result<Range<f64>, String> {
     }
 }
 
+fn parse_quality_weight(s: &str) -> Result<f64, String> {
+    match s {
+        "linear" => Ok(1.),
+        "squared" => Ok(2.),
+        _ => s.parse().map_err(|_| "Quality weighting must be 'linear', 'squared' or an exponent".to_string())
+    }
+}
+
 
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    // The output is always the last positional argument, as the inputs before
+    // it can be left out when they come from an archive or from fringes
+    let mut paths: Vec<PathBuf> = [args.unwrapped.take(), args.quality.take(), args.output.take()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let output = paths.pop().ok_or_else(|| anyhow::anyhow!("No output image given"))?;
+    let mut paths = paths.into_iter();
+
+    (args.unwrapped, args.quality) = (paths.next(), paths.next());
+
     let dim = (args.dimensions.0 as u32, args.dimensions.1 as u32);
-    let uphase = Array2::<f64>::read_npy(File::open(&args.unwrapped)?)?;
-    let quality = Array2::<f64>::read_npy(File::open(&args.quality)?)?;
+    let to_radians = |u: f64| u*args.phase_scale+args.phase_offset;
+
+    anyhow::ensure!(
+        args.unwrapped.is_none() || (args.fringes.is_none() && args.temporal.is_none()),
+        "Phase and quality inputs cannot be given with --fringes or --temporal"
+    );
+
+    let (mut uphase, quality, unwrap_method) = match (&args.fringes, &args.temporal, &args.unwrapped) {
+        (Some(fringes), _, _) => {
+            let stack = input::read_stack(fringes, &args.fringe_array)?;
+            let (wrapped, quality) = fringe::demodulate(stack.view(), args.fringe_quality)?;
+
+            println!("Demodulated {} phase shifted frames", stack.len_of(Axis(0)));
+            (wrapped, quality, Some(args.unwrap.unwrap_or(unwrap::Method::QualityGuided)))
+        },
+        (None, Some(wrapped), _) => {
+            let stack = input::read_stack(wrapped, &args.wrapped_array)?.mapv(to_radians);
+            let frequencies = args.frequencies.as_deref().unwrap_or_default();
+            let (uphase, quality) = temporal::unwrap(args.temporal_scheme, stack.view(), frequencies)?;
+
+            (uphase, quality, None)
+        },
+        (None, None, Some(unwrapped)) => {
+            let phase_array = if args.unwrap.is_some() { &args.wrapped_array } else { &args.phase_array };
+            let mut uphase = input::read_map(unwrapped, phase_array)?.mapv(to_radians);
+            let quality_path = match &args.quality {
+                Some(path) => path,
+                None if input::is_archive(unwrapped) => unwrapped,
+                None => anyhow::bail!("A quality map must be given unless the phase is read from an .npz archive")
+            };
+            let mut quality = input::read_map(quality_path, &args.quality_array)?;
+
+            if quality.dim() != uphase.dim() {
+                match args.shape_mismatch {
+                    Mismatch::Error => anyhow::bail!(
+                        "The quality {} has shape {:?}, but the phase {} has shape {:?} \
+                        (see --shape-mismatch to crop or resample the quality)",
+                        quality_path.display(), quality.dim(), unwrapped.display(), uphase.dim()
+                    ),
+                    Mismatch::Crop => {
+                        let (h, w) = (uphase.nrows().min(quality.nrows()), uphase.ncols().min(quality.ncols()));
+
+                        println!("Cropped the phase and quality to their common region of {w}x{h} pixels");
+                        uphase = uphase.slice_move(s![..h, ..w]);
+                        quality = quality.slice_move(s![..h, ..w]);
+                    },
+                    Mismatch::Resample => {
+                        println!("Resampled the quality from {:?} to {:?}", quality.dim(), uphase.dim());
+                        quality = input::resample(&quality, uphase.dim());
+                    }
+                }
+            }
+
+            (uphase, quality, args.unwrap)
+        },
+        (None, None, None) => anyhow::bail!("No unwrapped phase given")
+    };
     let (h, w) = uphase.dim();
 
+    let mask = match &args.mask {
+        Some(path) => {
+            let mask = input::read_map(path, &args.mask_array)?;
+
+            ensure_shape("mask", path, mask.dim(), uphase.dim())?;
+            Some(mask.mapv(|m| m != 0.))
+        },
+        None => None
+    };
+
+    if let Some(method) = unwrap_method {
+        let mut valid = Zip::from(&uphase).and(&quality)
+            .map_collect(|&p, &q| p.is_finite() && q.is_finite() && q > args.threshold);
+
+        if let Some(mask) = &mask {
+            valid &= mask;
+        }
+
+        uphase = unwrap::unwrap(method, &uphase, &quality, &valid);
+
+        let unwrapped = uphase.iter().filter(|u| !u.is_nan()).count();
+
+        println!("Unwrapped {unwrapped} of {} valid pixels", valid.iter().filter(|&&v| v).count());
+    }
+
+    if let Some(path) = &args.save_unwrapped {
+        uphase.write_npy(BufWriter::new(File::create(path)?))?;
+    }
+
+    let reference = match &args.reference {
+        Some(path) => {
+            let reference = input::read_map(path, &args.phase_array)?.mapv(to_radians);
+            let reference_quality = match &args.reference_quality {
+                Some(path) => input::read_map(path, &args.quality_array)?,
+                None => Array2::<f64>::from_elem(reference.dim(), f64::INFINITY)
+            };
+
+            ensure_shape("reference", path, reference.dim(), uphase.dim())?;
+
+            if let Some(path) = &args.reference_quality {
+                ensure_shape("reference quality", path, reference_quality.dim(), uphase.dim())?;
+            }
+
+            Some((reference, reference_quality))
+        },
+        None => None
+    };
+    let reference_threshold = args.reference_threshold.unwrap_or(args.threshold);
+
+    let camera = args.focal_length.map(|focal_length| {
+        let principal_point = args.principal_point
+            .unwrap_or(camera::PrincipalPoint((w as f64-1.)/2., (h as f64-1.)/2.));
+        let distortion = args.distortion.unwrap_or(camera::Distortion(0., 0.));
+
+        camera::Camera {
+            focal_length,
+            principal_point: (principal_point.0, principal_point.1),
+            pixel_pitch: args.pixel_pitch.unwrap(),
+            distortion: (distortion.0, distortion.1),
+            working_distance: args.working_distance.unwrap()
+        }
+    });
+
+    // Each point is [x, y, z, quality, column, row], where x and y are the
+    // lateral position, in millimetres if the camera is known and otherwise
+    // the same as the pixel column and row
     let mut data = vec![];
-    let (mut min_u, mut max_u) = (f64::MAX, f64::MIN);
+    let (mut non_finite, mut low_quality, mut masked, mut bad_reference) = (0, 0, 0, 0);
 
     azip!((index (i, j), &u in &uphase, &q in &quality) {
-        if q > args.threshold {
-            data.extend([j as f64, i as f64, u, q]);
+        // Unwrappers mark failed pixels with NaN, so any non-finite value is
+        // taken to be invalid
+        if !q.is_finite() {
+            non_finite += 1;
+        }
+        else if q <= args.threshold {
+            low_quality += 1;
+        }
+        else if mask.as_ref().is_some_and(|m| !m[[i, j]]) {
+            masked += 1;
+        }
+        else if !u.is_finite() {
+            non_finite += 1;
+        }
+        else if reference.as_ref().is_some_and(|(r, rq)| {
+            !r[[i, j]].is_finite() || rq[[i, j]].is_nan() || rq[[i, j]] <= reference_threshold
+        }) {
+            bad_reference += 1;
+        }
+        else {
+            let z = reference.as_ref().map_or(u, |(r, _)| u-r[[i, j]]);
+            let (x, y) = match &camera {
+                Some(camera) => camera.lateral(j as f64, i as f64),
+                None => (j as f64, i as f64)
+            };
 
-            if u > max_u { max_u = u; }
-            if u < min_u { min_u = u; }
+            data.extend([x, y, z, q, j as f64, i as f64]);
         }
     });
     
-    let n_points = data.len()/4;
-    let mut data = Array2::from_shape_vec((n_points, 4), data).unwrap();
-    
-    let coeffs = if let Some(coeffs) = args.fit_coefficients {
-        Array1::<f64>::from_vec(coeffs)
+    let n_points = data.len()/6;
+    let mut data = Array2::from_shape_vec((n_points, 6), data).unwrap();
+
+    if n_points < h*w {
+        let reasons: Vec<String> = [
+            (non_finite, "non-finite"),
+            (low_quality, "below the quality threshold"),
+            (masked, "masked out"),
+            (bad_reference, "invalid in the reference")
+        ]
+            .iter()
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, reason)| format!("{n} {reason}"))
+            .collect();
+
+        println!("Excluded {} of {} pixels: {}", h*w-n_points, h*w, reasons.join(", "));
+    }
+
+    anyhow::ensure!(n_points > 0, "No usable points are left, so there is nothing to fit or plot");
+
+    if reference.is_some() {
+        // The two maps are each only defined up to a multiple of 2π, so remove
+        // the nearest one to the median difference between them
+        let k = (fit::median(data.column(2).to_vec())/TAU).round();
+
+        data.column_mut(2).mapv_inplace(|z| z-k*TAU);
+        println!("Subtracted the reference map at {n_points} points, with an offset of {k}·2π");
+    }
+
+    let calibration = if let Some(scale) = args.height_scale {
+        Some(calibration::Calibration::Linear(scale))
+    }
+    else if let Some(triangulation) = args.triangulation {
+        Some(calibration::Calibration::Triangulation(triangulation))
+    }
+    else if let Some(path) = &args.height_calibration {
+        Some(calibration::Calibration::from_polynomial(path, &args.calibration_array, uphase.dim())?)
     }
     else {
-        let coeffs = plane_fit(data.view());
+        None
+    };
 
-        println!("Successfully fit (ax+by+c)/(dx+ey+1) to the data with:");
-        
-        for (c, v) in ('a'..='e').zip(&coeffs) {
-            println!("  {c} = {v}");
+    let (mut min_u, mut max_u) = data.column(2).fold((f64::MAX, f64::MIN), |(lo, hi), &u| (lo.min(u), hi.max(u)));
+    
+    if !args.no_fit {
+        let (mut spec, loaded) = match &args.load_fit {
+            Some(path) => {
+                let (spec, coeffs) = fitfile::load(path)?;
+
+                (spec, Some(coeffs))
+            },
+            None => {
+                let spec = ModelSpec {
+                    kind: args.model,
+                    order: args.order,
+                    aperture: args.aperture,
+                    indexing: args.zernike_indexing,
+                    pixel_scale: args.pixel_scale
+                };
+
+                (spec, args.fit_coefficients.clone().map(Array1::from_vec))
+            }
+        };
+
+        if spec.kind == ModelKind::Zernike {
+            let aperture = *spec.aperture.get_or_insert_with(|| zernike::Aperture::fit(data.view()));
+            let inside: Vec<usize> = (0..n_points)
+                .filter(|&i| aperture.contains(data[[i, 0]], data[[i, 1]]))
+                .collect();
+
+            anyhow::ensure!(
+                !inside.is_empty(),
+                "The Zernike aperture at ({}, {}) with radius {} contains none of the {n_points} usable points",
+                aperture.cx, aperture.cy, aperture.radius
+            );
+
+            data = data.select(Axis(0), &inside);
         }
 
-        coeffs
-    };
+        let model = spec.build();
+
+        let (coeffs, outliers) = if let Some(coeffs) = loaded {
+            let names = model.param_names();
+
+            if coeffs.len() != names.len() {
+                anyhow::bail!(
+                    "The {} model has {} coefficients ({}), but {} were given",
+                    model.name(), names.len(), names.join(", "), coeffs.len()
+                );
+            }
+
+            (coeffs, Array1::from_elem(data.nrows(), false))
+        }
+        else {
+            let roi = if let Some(rect) = args.roi_rect {
+                Some(roi::Roi::from_rect(rect))
+            }
+            else if let Some(polygon) = &args.roi_polygon {
+                Some(roi::Roi::from_polygon(polygon.clone()))
+            }
+            else if let Some(path) = &args.roi_mask {
+                let mask = input::read_map(path, &args.mask_array)?;
+
+                ensure_shape("ROI mask", path, mask.dim(), (h, w))?;
+                Some(roi::Roi::from_mask(mask.mapv(|m| m != 0.)))
+            }
+            else {
+                None
+            };
+
+            let fitted: Vec<usize> = match roi {
+                Some(roi) => {
+                    let inside: Vec<usize> = (0..data.nrows())
+                        .filter(|&i| roi.contains(data[[i, 4]], data[[i, 5]]))
+                        .collect();
+
+                    println!("Fitting to the {} of {} points in the region of interest", inside.len(), data.nrows());
+                    inside
+                },
+                None => (0..data.nrows()).collect()
+            };
+
+            let (coeffs, rejected) = fit_model(
+                &args, &spec, model.as_ref(), calibration.as_ref(), data.select(Axis(0), &fitted).view()
+            )?;
+            let mut outliers = Array1::from_elem(data.nrows(), false);
+
+            for (&i, &r) in fitted.iter().zip(&rejected) {
+                outliers[i] = r;
+            }
+
+            (coeffs, outliers)
+        };
 
-    for mut p in data.rows_mut() {
-        p[2] -= (coeffs[0]*p[0]+coeffs[1]*p[1]+coeffs[2])/(coeffs[3]*p[0]+coeffs[4]*p[1]+1.);
+        let surface = removed_surface(&args, model.as_ref(), &coeffs, data.view())?;
+        let mut zs = data.slice_mut(s![.., 2]);
+
+        zs -= &surface;
+
+        if args.exclude_outliers {
+            let kept: Vec<usize> = (0..data.nrows()).filter(|&i| !outliers[i]).collect();
+
+            println!("Excluded {} outliers from the plot", data.nrows()-kept.len());
+            data = data.select(Axis(0), &kept);
+        }
     }
 
-    let mut zs = data.slice_mut(s![.., 2]);
+    // Multiples of 2π are an ambiguity in the phase itself, so are snapped off
+    // before conversion to height, but triangulation needs the phase relative
+    // to the reference plane, so the mean is only removed after it
+    if args.snap_offset {
+        let mut zs = data.slice_mut(s![.., 2]);
+        let offset = zs.mean().unwrap();
+        let k = (offset/TAU).round();
 
-    zs -= zs.mean().unwrap();
+        zs -= k*TAU;
+        println!("Residual offset of {offset} rad snapped to {k}·2π");
+    }
+
+    if let Some(calibration) = &calibration {
+        for mut p in data.rows_mut() {
+            p[2] = calibration.height(p[2], p[4], p[5]);
+        }
+    }
+
+    // Removing the mean after only the piston of the fit would undo it
+    let piston_only = !args.no_fit && matches!(args.remove, Removal::Piston);
+
+    if !args.snap_offset && !piston_only {
+        let mut zs = data.slice_mut(s![.., 2]);
+        let offset = zs.mean().unwrap();
+
+        zs -= offset;
+    }
+
+    if calibration.is_some() {
+        let heights = data.column(2);
+        let mean = heights.mean().unwrap_or(0.);
+        let rms = heights.mapv(|h| (h-mean).powi(2)).mean().unwrap_or(0.).sqrt();
+
+        (min_u, max_u) = heights.fold((f64::MAX, f64::MIN), |(lo, hi), &h| (lo.min(h), hi.max(h)));
+        println!(
+            "Residual height RMS = {rms} {unit}, peak to valley = {} {unit}",
+            max_u-min_u, unit = args.height_unit
+        );
+    }
+
+    let zlabel = match calibration {
+        Some(_) => format!("height / {}", args.height_unit),
+        None => "depth / rad".to_string()
+    };
 
     let cmap = colorous::RAINBOW;
-    let xlim = 0.0..(w as f64);
-    let ylim = (h as f64)..0.0;
+    let (xlim, ylim, lateral_unit) = if camera.is_some() {
+        let range = |col: usize| data.column(col).fold(f64::MAX..f64::MIN, |r, &v| r.start.min(v)..r.end.max(v));
+        let (xlim, ylim) = (range(0), range(1));
+
+        (xlim, ylim.end..ylim.start, "mm")
+    }
+    else {
+        (0.0..(w as f64), (h as f64)..0.0, "pixels")
+    };
     let zlim = args.zlim.unwrap_or(min_u..max_u);
     
     let data_file = NamedTempFile::new_in("")?;
@@ -162,17 +856,25 @@ fn main() -> anyhow::Result<()> {
     
     drop(writer);
 
+    if let Some(path) = &args.save_points {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        for p in data.rows() {
+            writeln!(writer, "{} {} {}", p[0], p[1], p[2])?;
+        }
+    }
+
     let data_path = data_file.into_temp_path();
     
     // General plot configuration
     writeln!(plot_file, "set term {} size {},{}", args.backend, dim.0, dim.1)?;
-    writeln!(plot_file, "set output '{}'", args.output.display())?;
+    writeln!(plot_file, "set output '{}'", output.display())?;
     writeln!(plot_file, "set xrange [{}:{}]", xlim.start, xlim.end)?;
     writeln!(plot_file, "set zrange [{}:{}]", ylim.start, ylim.end)?;
     writeln!(plot_file, "set yrange [{}:{}]", zlim.start, zlim.end)?;
-    writeln!(plot_file, "set xlabel 'x / pixels' offset screen 0,-0.02")?;
-    writeln!(plot_file, "set ylabel 'depth / rad' offset screen 0,-0.02")?;
-    writeln!(plot_file, "set zlabel 'y / pixels' rotate")?;
+    writeln!(plot_file, "set xlabel 'x / {lateral_unit}' offset screen 0,-0.02")?;
+    writeln!(plot_file, "set ylabel '{zlabel}' offset screen 0,-0.02")?;
+    writeln!(plot_file, "set zlabel 'y / {lateral_unit}' rotate")?;
     writeln!(plot_file, "set view 75, 20")?;
     writeln!(plot_file, "set xyplane 0")?;
     writeln!(plot_file, "set multiplot")?;
@@ -199,23 +901,238 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-// Fit the equation z = (ax+by+c)/(dx+ey+1) to the given array of points,
-// which is a good approximation to the general equation for the phase image
-// produced by a plane target. Subtract this fit from the array.
-// Each row of `points` is [x, y, z].
-// Returns the fit coefficients [a, b, c, d, e].
-fn plane_fit(points: ArrayView2<f64>) -> Array1<f64> {
-    let xy = points.slice(s![.., ..2]);
-    let z = points.slice(s![.., 2]);
-    let mut matrix = Array2::<f64>::ones((points.nrows(), 5)); // [[-x, -y, 1, xz, yz], ...]
+// Fit `model`, built from `spec`, to `data` as chosen by `args`, reporting
+// the result. Also returns which points the fit rejected as outliers, i.e.
+// those given no weight by the method despite having some to begin with. With
+// a `calibration`, the residuals are reported as heights, and the
+// uncertainties of the coefficients, which are in radians, are left out.
+fn fit_model(
+    args: &Args,
+    spec: &ModelSpec,
+    model: &dyn SurfaceModel,
+    calibration: Option<&calibration::Calibration>,
+    data: ArrayView2<f64>
+) -> anyhow::Result<(Array1<f64>, Array1<bool>)> {
+    let n_points = data.nrows();
+    let names = model.param_names();
+    let labels = model.param_labels();
+
+    anyhow::ensure!(
+        n_points >= names.len(),
+        "Only {n_points} points are left to fit, but the {} model has {} coefficients",
+        model.name(), names.len()
+    );
+
+    let weights = match args.quality_weight {
+        Some(p) => data.column(3).mapv(|q| q.max(0.).powf(p)),
+        None => Array1::ones(n_points)
+    };
+
+    // The weights each method settles on are reused for refinement, so that
+    // RANSAC outliers and points down-weighted by IRLS stay that way
+    let (coeffs, fit_weights, mut notes) = match args.method {
+        FitMethod::LeastSquares => {
+            let coeffs = model.fit(data, weights.view());
+
+            (coeffs, weights.clone(), vec![])
+        },
+        FitMethod::Ransac => {
+            let mut rng = match args.ransac_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy()
+            };
+            let fit = fit::ransac_fit(
+                model, data, weights.view(), args.ransac_tolerance, args.ransac_iterations, &mut rng
+            )?;
+
+            let notes = vec![format!("inliers = {:.2}% of {n_points} points", 100.*fit.inlier_fraction)];
+            let weights = &weights*&fit.inliers.mapv(|b| if b { 1. } else { 0. });
+
+            (fit.coeffs, weights, notes)
+        },
+        FitMethod::Irls => {
+            let fit = fit::irls_fit(
+                model, data, weights.view(), args.loss, args.irls_scale, args.irls_iterations
+            )?;
+            let status = if fit.converged { "converged" } else { "did not converge" };
+            let notes = vec![format!("IRLS {status} after {} iterations", fit.iterations)];
+
+            (fit.coeffs, fit.weights, notes)
+        },
+        FitMethod::SigmaClip => {
+            let fit = fit::sigma_clip_fit(
+                model, data, weights.view(), args.clip_sigma, args.clip_mad, args.clip_iterations
+            )?;
+            let status = if fit.converged { "converged" } else { "did not converge" };
+            let rejected = fit.kept.iter().filter(|&&k| !k).count();
+            let notes = vec![format!(
+                "sigma clipping rejected {rejected} of {n_points} points, {status} after {} iterations",
+                fit.iterations
+            )];
+            let weights = &weights*&fit.kept.mapv(|b| if b { 1. } else { 0. });
+
+            (fit.coeffs, weights, notes)
+        }
+    };
+
+    let rejected = Zip::from(&weights).and(&fit_weights).map_collect(|&w, &f| w > 0. && f <= 0.);
+    let weights = fit_weights;
+
+    let coeffs = if args.refine {
+        let fit = fit::refine_fit(model, data, weights.view(), coeffs.view(), args.refine_iterations);
+        let status = if fit.converged { "converged" } else { "did not converge" };
+
+        notes.push(format!(
+            "Levenberg-Marquardt {status} after {} iterations, RMS residual {:.6} -> {:.6}",
+            fit.iterations, fit.initial_rms, fit.final_rms
+        ));
+
+        fit.coeffs
+    }
+    else {
+        coeffs
+    };
+
+    let mut stats = match fit::fit_statistics(model, data, weights.view(), coeffs.view()) {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            eprintln!("Warning: {e}");
+            None
+        }
+    };
+    let unit = match calibration {
+        Some(_) => args.height_unit.as_str(),
+        None => "rad"
+    };
+
+    if let (Some(calibration), Some(stats)) = (calibration, &mut stats) {
+        let residuals = fit::residuals(model, data, coeffs.view());
+        let heights = Zip::from(&residuals).and(data.rows())
+            .map_collect(|&r, p| calibration.height(r, p[4], p[5]));
+
+        (stats.rms, stats.peak_to_valley) = fit::residual_spread(&heights, weights.view());
+        notes.push("uncertainties are not given, as the coefficients are of the phase in rad".to_string());
+    }
+
+    if let Some(stats) = stats.as_ref().filter(|s| s.condition_number > fit::POOR_CONDITION) {
+        eprintln!(
+            "Warning: the fit is poorly conditioned (condition number {:.3e}), so the coefficients may be unreliable",
+            stats.condition_number
+        );
+    }
+
+    println!("Successfully fit {} to the data with:", model.name());
     
-    matrix.slice_mut(s![.., ..2]).assign(&xy);
-    matrix.slice_mut(s![.., 3..]).assign(&xy);
-    matrix.slice_mut(s![.., 3]).mul_assign(&z);
-    matrix.slice_mut(s![.., 4]).mul_assign(&z);
-    matrix.slice_mut(s![.., 3..]).mul_assign(-1.);
-
-    matrix.least_squares(&z)
-        .expect("Could not find least squares fit for given points")
-        .solution
+    for (i, (c, v)) in labels.iter().zip(&coeffs).enumerate() {
+        match &stats {
+            Some(stats) if calibration.is_none() => println!("  {c} = {v} ± {}", stats.std_errors[i]),
+            _ => println!("  {c} = {v}")
+        }
+    }
+
+    for note in notes {
+        println!("  {note}");
+    }
+
+    if let Some(stats) = &stats {
+        println!("  residual RMS = {} {unit}, peak to valley = {} {unit}", stats.rms, stats.peak_to_valley);
+        println!("  condition number = {:.3e}", stats.condition_number);
+        println!("  correlations:");
+
+        let width = names.iter().map(String::len).max().unwrap_or(0);
+
+        println!("    {:width$}{}", "", names.iter().map(|c| format!("{c:>8}")).collect::<String>());
+
+        for (c, row) in names.iter().zip(stats.correlation.rows()) {
+            println!("    {c:width$}{}", row.iter().map(|r| format!("{r:>8.3}")).collect::<String>());
+        }
+    }
+
+    if let Some(path) = &args.save_fit {
+        let method = args.method.to_possible_value().unwrap();
+        let mut inputs = vec![];
+
+        // Stacks are recorded one file at a time, under the same name
+        for path in args.fringes.iter().flatten() {
+            inputs.push(("fringes", path.as_path()));
+        }
+
+        for path in args.temporal.iter().flatten() {
+            inputs.push(("temporal", path.as_path()));
+        }
+
+        let files = [
+            ("unwrapped", &args.unwrapped),
+            ("quality", &args.quality),
+            ("reference", &args.reference),
+            ("reference_quality", &args.reference_quality),
+            ("mask", &args.mask),
+            ("roi_mask", &args.roi_mask),
+            ("height_calibration", &args.height_calibration)
+        ];
+
+        for (name, path) in files {
+            if let Some(path) = path {
+                inputs.push((name, path.as_path()));
+            }
+        }
+
+        fitfile::save(path, &fitfile::FitRecord {
+            spec,
+            model,
+            method: method.get_name(),
+            coeffs: coeffs.view(),
+            stats: stats.as_ref(),
+            height_unit: calibration.map(|_| unit),
+            inputs,
+            threshold: args.threshold,
+            points: n_points
+        })?;
+    }
+
+    Ok((coeffs, rejected))
+}
+
+// The part of the fitted surface to remove from each of `data`, as chosen by
+// `args`.
+fn removed_surface(
+    args: &Args,
+    model: &dyn SurfaceModel,
+    coeffs: &Array1<f64>,
+    data: ArrayView2<f64>
+) -> anyhow::Result<Array1<f64>> {
+    let names = model.param_names();
+    let mut removed = coeffs.clone();
+
+    if let Some(terms) = &args.remove_terms {
+        for term in terms {
+            if !names.contains(term) {
+                anyhow::bail!("No term named {term} in the fit (terms are {})", names.join(", "));
+            }
+        }
+
+        for (c, name) in removed.iter_mut().zip(&names) {
+            if !terms.contains(name) {
+                *c = 0.;
+            }
+        }
+    }
+
+    let surface: Array1<f64> = data.rows().into_iter()
+        .map(|p| model.evaluate(removed.view(), p[0], p[1]))
+        .collect();
+    let surface = match args.remove {
+        Removal::Full => surface,
+        Removal::Piston => {
+            let piston = model.piston(removed.view()).ok_or_else(|| {
+                anyhow::anyhow!("The {} model has no constant term to remove on its own", model.name())
+            })?;
+
+            Array1::from_elem(data.nrows(), piston)
+        },
+        Removal::Tilt => fit::plane_component(data, surface.view()),
+        Removal::Perspective => &surface-&fit::plane_component(data, surface.view())
+    };
+
+    Ok(surface)
 }
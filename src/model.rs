@@ -0,0 +1,412 @@
+use std::ops::MulAssign;
+use ndarray::prelude::*;
+use ndarray_linalg::{LeastSquaresSvd, SVD};
+use crate::zernike::{Aperture, Indexing, Zernike};
+use crate::primitive::{Sphere, Cylinder};
+
+
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ModelKind {
+    /// The projective plane (ax+by+c)/(dx+ey+1)
+    Rational,
+    /// A bivariate polynomial of order --order
+    Polynomial,
+    /// Zernike polynomials up to radial order --order over a circular aperture
+    Zernike,
+    /// A sphere, for calibrating on reference balls
+    Sphere,
+    /// A cylinder with its axis roughly in the image plane
+    Cylinder
+}
+
+// Everything needed to construct a model. Only the settings relevant to its
+// kind are used, and a Zernike model must have its aperture set.
+pub struct ModelSpec {
+    pub kind: ModelKind,
+    pub order: u32,
+    pub aperture: Option<Aperture>,
+    pub indexing: Indexing,
+    pub pixel_scale: f64
+}
+
+// A parametric surface z = f(x, y) that can be fit to and removed from the
+// data. Coefficients are always expressed in the units of the points given to
+// `fit`, so that they can be supplied again on the command line.
+pub trait SurfaceModel {
+    // Description of the surface, used when reporting the fit.
+    fn name(&self) -> String;
+
+    // Names of the coefficients, in the order they are returned by `fit`.
+    fn param_names(&self) -> Vec<String>;
+
+    // Names of the coefficients for display, which may be more descriptive.
+    fn param_labels(&self) -> Vec<String> {
+        self.param_names()
+    }
+
+    fn param_count(&self) -> usize {
+        self.param_names().len()
+    }
+
+    // The constant term of the surface given by `coeffs`, if the model has
+    // one.
+    fn piston(&self, _coeffs: ArrayView1<f64>) -> Option<f64> {
+        None
+    }
+
+    // Weighted least squares fit to the given points, each row of which is
+    // [x, y, z, ...]. Each point's squared residual is multiplied by the
+    // corresponding entry of `weights`, which must be non-negative.
+    fn fit(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Array1<f64>;
+
+    fn evaluate(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> f64;
+
+    // Partial derivatives of the surface at (x, y) with respect to each
+    // coefficient.
+    fn gradient(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> Array1<f64>;
+
+    // Condition number of the linear problem that `fit` solves, or for
+    // nonlinear models of the Jacobian at `coeffs`. Large values mean the
+    // coefficients are poorly determined by the data regardless of the units
+    // they are expressed in.
+    fn condition_number(
+        &self,
+        points: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        coeffs: ArrayView1<f64>
+    ) -> anyhow::Result<f64>;
+}
+
+// The surface z = (ax+by+c)/(dx+ey+1), which is a good approximation to the
+// general equation for the phase image produced by a plane target.
+pub struct RationalPlane;
+
+// A bivariate polynomial of the given total order, with one coefficient per
+// monomial x^i y^j where i+j <= order.
+pub struct Polynomial {
+    terms: Vec<(i32, i32)>
+}
+
+// Hartley-style normalisation of a set of points. x and y are translated so
+// that their weighted centroid is at the origin and scaled so that the mean
+// distance from it is √2, while z is translated and scaled to have zero mean
+// and unit standard deviation.
+struct Normalisation {
+    x0: f64,
+    y0: f64,
+    xy_scale: f64,
+    z0: f64,
+    z_scale: f64
+}
+
+
+
+impl ModelSpec {
+    pub fn build(&self) -> Box<dyn SurfaceModel> {
+        match self.kind {
+            ModelKind::Rational => Box::new(RationalPlane),
+            ModelKind::Polynomial => Box::new(Polynomial::new(self.order)),
+            ModelKind::Zernike => {
+                let aperture = self.aperture.expect("Zernike model built without an aperture");
+
+                Box::new(Zernike::new(self.order, aperture, self.indexing))
+            },
+            ModelKind::Sphere => Box::new(Sphere { scale: self.pixel_scale }),
+            ModelKind::Cylinder => Box::new(Cylinder { scale: self.pixel_scale })
+        }
+    }
+}
+
+impl SurfaceModel for RationalPlane {
+    fn name(&self) -> String {
+        "(ax+by+c)/(dx+ey+1)".to_string()
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        ('a'..='e').map(String::from).collect()
+    }
+
+    // The equation is linearised to z(dx+ey+1) = ax+by+c, and solved in
+    // normalised coordinates (see `Normalisation`), since raw pixel
+    // coordinates and their products with z make for a badly conditioned
+    // design matrix on large sensors.
+    fn fit(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Array1<f64> {
+        let norm = Normalisation::new(points, weights);
+        let (matrix, z) = self.design_matrix(norm.apply(points).view(), weights);
+
+        let solution = matrix.least_squares(&z)
+            .expect("Could not find least squares fit for given points")
+            .solution;
+
+        norm.restore_rational(solution.view())
+    }
+
+    fn evaluate(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> f64 {
+        (coeffs[0]*x+coeffs[1]*y+coeffs[2])/(coeffs[3]*x+coeffs[4]*y+1.)
+    }
+
+    fn gradient(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> Array1<f64> {
+        let den = coeffs[3]*x+coeffs[4]*y+1.;
+        let f = self.evaluate(coeffs, x, y);
+
+        array![x/den, y/den, 1./den, -x*f/den, -y*f/den]
+    }
+
+    fn piston(&self, coeffs: ArrayView1<f64>) -> Option<f64> {
+        Some(coeffs[2])
+    }
+
+    fn condition_number(
+        &self,
+        points: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        _coeffs: ArrayView1<f64>
+    ) -> anyhow::Result<f64> {
+        let norm = Normalisation::new(points, weights);
+        let (matrix, _) = self.design_matrix(norm.apply(points).view(), weights);
+
+        condition_number(matrix)
+    }
+}
+
+impl RationalPlane {
+    // Linear least squares system for z(dx+ey+1) = ax+by+c, with each
+    // equation scaled by the square root of its weight.
+    fn design_matrix(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> (Array2<f64>, Array1<f64>) {
+        let xy = points.slice(s![.., ..2]);
+        let z = points.slice(s![.., 2]);
+        let mut matrix = Array2::<f64>::ones((points.nrows(), 5)); // [[-x, -y, 1, xz, yz], ...]
+        let sqrt_w = weights.mapv(f64::sqrt);
+
+        matrix.slice_mut(s![.., ..2]).assign(&xy);
+        matrix.slice_mut(s![.., 3..]).assign(&xy);
+        matrix.slice_mut(s![.., 3]).mul_assign(&z);
+        matrix.slice_mut(s![.., 4]).mul_assign(&z);
+        matrix.slice_mut(s![.., 3..]).mul_assign(-1.);
+        matrix.mul_assign(&sqrt_w.view().insert_axis(Axis(1)));
+
+        (matrix, &z*&sqrt_w)
+    }
+}
+
+impl Polynomial {
+    pub fn new(order: u32) -> Self {
+        let order = order as i32;
+        let terms = (0..=order)
+            .flat_map(|d| (0..=d).map(move |j| (d-j, j)))
+            .collect();
+
+        Self { terms }
+    }
+
+    // Weighted Vandermonde matrix with one column per term.
+    fn design_matrix(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> (Array2<f64>, Array1<f64>) {
+        let sqrt_w = weights.mapv(f64::sqrt);
+        let mut matrix = Array2::<f64>::zeros((points.nrows(), self.terms.len()));
+
+        for ((p, mut row), w) in points.rows().into_iter().zip(matrix.rows_mut()).zip(&sqrt_w) {
+            for (m, &(i, j)) in row.iter_mut().zip(&self.terms) {
+                *m = w*p[0].powi(i)*p[1].powi(j);
+            }
+        }
+
+        (matrix, &points.column(2)*&sqrt_w)
+    }
+
+    fn index_of(&self, term: (i32, i32)) -> usize {
+        self.terms.iter().position(|&t| t == term).unwrap()
+    }
+}
+
+impl SurfaceModel for Polynomial {
+    fn name(&self) -> String {
+        let order = self.terms.last().map_or(0, |&(i, j)| i+j);
+
+        format!("polynomial of order {order}")
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        self.terms.iter()
+            .map(|&(i, j)| match (i, j) {
+                (0, 0) => "1".to_string(),
+                _ => format!("{}{}", monomial('x', i), monomial('y', j))
+            })
+            .collect()
+    }
+
+    // Solved in normalised coordinates, with the polynomial then expanded
+    // binomially to get the coefficients in the original coordinates.
+    fn fit(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Array1<f64> {
+        let norm = Normalisation::new(points, weights);
+        let (matrix, z) = self.design_matrix(norm.apply(points).view(), weights);
+
+        let solution = matrix.least_squares(&z)
+            .expect("Could not find least squares fit for given points")
+            .solution;
+
+        let mut coeffs = Array1::<f64>::zeros(self.terms.len());
+
+        for (&c, &(i, j)) in solution.iter().zip(&self.terms) {
+            let c = c*norm.z_scale/norm.xy_scale.powi(i+j);
+
+            for k in 0..=i {
+                for l in 0..=j {
+                    coeffs[self.index_of((k, l))] += c
+                        *binomial(i, k)*(-norm.x0).powi(i-k)
+                        *binomial(j, l)*(-norm.y0).powi(j-l);
+                }
+            }
+        }
+
+        coeffs[0] += norm.z0;
+        coeffs
+    }
+
+    fn evaluate(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> f64 {
+        coeffs.iter().zip(&self.terms)
+            .map(|(c, &(i, j))| c*x.powi(i)*y.powi(j))
+            .sum()
+    }
+
+    fn gradient(&self, _coeffs: ArrayView1<f64>, x: f64, y: f64) -> Array1<f64> {
+        self.terms.iter().map(|&(i, j)| x.powi(i)*y.powi(j)).collect()
+    }
+
+    fn piston(&self, coeffs: ArrayView1<f64>) -> Option<f64> {
+        Some(coeffs[self.index_of((0, 0))])
+    }
+
+    fn condition_number(
+        &self,
+        points: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        _coeffs: ArrayView1<f64>
+    ) -> anyhow::Result<f64> {
+        let norm = Normalisation::new(points, weights);
+        let (matrix, _) = self.design_matrix(norm.apply(points).view(), weights);
+
+        condition_number(matrix)
+    }
+}
+
+impl Normalisation {
+    fn new(points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Self {
+        let total = weights.sum();
+        let mean = |col: usize| points.column(col).dot(&weights)/total;
+        let (x0, y0, z0) = (mean(0), mean(1), mean(2));
+        let mut mean_dist = 0.;
+        let mut z_var = 0.;
+
+        for (p, w) in points.rows().into_iter().zip(&weights) {
+            mean_dist += w*(p[0]-x0).hypot(p[1]-y0);
+            z_var += w*(p[2]-z0).powi(2);
+        }
+
+        let mean_dist = mean_dist/total;
+        let z_std = (z_var/total).sqrt();
+
+        Self {
+            x0,
+            y0,
+            xy_scale: if mean_dist > 0. { mean_dist/2f64.sqrt() } else { 1. },
+            z0,
+            z_scale: if z_std > 0. { z_std } else { 1. }
+        }
+    }
+
+    // Normalised [x, y, z] for each row of `points`.
+    fn apply(&self, points: ArrayView2<f64>) -> Array2<f64> {
+        let mut normalised = points.slice(s![.., ..3]).to_owned();
+
+        normalised.column_mut(0).mapv_inplace(|x| (x-self.x0)/self.xy_scale);
+        normalised.column_mut(1).mapv_inplace(|y| (y-self.y0)/self.xy_scale);
+        normalised.column_mut(2).mapv_inplace(|z| (z-self.z0)/self.z_scale);
+        normalised
+    }
+
+    // Transform rational plane coefficients fit in normalised coordinates back
+    // to the original ones. Substituting the normalisation into the model
+    // gives a denominator whose constant term `k` is not 1, so everything is
+    // divided through by it. If `k` is 0 the surface can't be written in the
+    // original form and the coefficients will not be finite.
+    fn restore_rational(&self, c: ArrayView1<f64>) -> Array1<f64> {
+        let s = self.xy_scale;
+        let t = self.z_scale;
+        let k = 1.-(c[3]*self.x0+c[4]*self.y0)/s;
+        let c0 = c[2]-(c[0]*self.x0+c[1]*self.y0)/s;
+
+        array![
+            (self.z0*c[3]+t*c[0])/(s*k),
+            (self.z0*c[4]+t*c[1])/(s*k),
+            self.z0+t*c0/k,
+            c[3]/(s*k),
+            c[4]/(s*k)
+        ]
+    }
+}
+
+
+
+// Ratio of the largest to smallest singular value of `matrix`.
+pub fn condition_number(matrix: Array2<f64>) -> anyhow::Result<f64> {
+    let (_, singular, _) = matrix.svd(false, false)?;
+
+    Ok(singular[0]/singular[singular.len()-1])
+}
+
+fn monomial(var: char, power: i32) -> String {
+    match power {
+        0 => String::new(),
+        1 => var.to_string(),
+        _ => format!("{var}^{power}")
+    }
+}
+
+fn binomial(n: i32, k: i32) -> f64 {
+    (0..k).fold(1., |acc, i| acc*(n-i) as f64/(i+1) as f64)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points on a grid away from the origin, so that the normalisation has
+    // to move and scale them, with z given by `model` and `coeffs`.
+    fn grid(model: &dyn SurfaceModel, coeffs: &Array1<f64>) -> Array2<f64> {
+        let mut points = vec![];
+
+        for i in 0..20 {
+            for j in 0..30 {
+                let (x, y) = (100.+2.*j as f64, 50.+3.*i as f64);
+
+                points.extend([x, y, model.evaluate(coeffs.view(), x, y)]);
+            }
+        }
+
+        Array2::from_shape_vec((points.len()/3, 3), points).unwrap()
+    }
+
+    fn assert_round_trip(model: &dyn SurfaceModel, coeffs: Array1<f64>) {
+        let points = grid(model, &coeffs);
+        let fitted = model.fit(points.view(), Array1::ones(points.nrows()).view());
+
+        for (name, (&f, &c)) in model.param_names().iter().zip(fitted.iter().zip(&coeffs)) {
+            assert!((f-c).abs() <= 1e-8*c.abs().max(1.), "{name}: fit {f}, expected {c}");
+        }
+    }
+
+    #[test]
+    fn rational_plane_round_trip() {
+        assert_round_trip(&RationalPlane, array![0.3, -0.2, 5., 1e-3, -2e-3]);
+    }
+
+    #[test]
+    fn polynomial_round_trip() {
+        let coeffs = array![2., 0.1, -0.3, 1e-3, 2e-3, -1e-3, 1e-5, -2e-5, 3e-5, 1e-5];
+
+        assert_round_trip(&Polynomial::new(3), coeffs);
+    }
+}
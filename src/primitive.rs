@@ -0,0 +1,207 @@
+use ndarray::prelude::*;
+use ndarray_linalg::LeastSquaresSvd;
+use crate::model::{self, SurfaceModel, Polynomial};
+use crate::fit;
+
+
+
+// Iterations of Levenberg-Marquardt used to polish the initial estimates.
+const REFINE_ITERATIONS: usize = 100;
+
+// A sphere z = zc ± √(R²-(x-xc)²-(y-yc)²), with the sign given by that of the
+// radius, so that a positive radius is a cap bulging towards +z.
+// x and y are multiplied by `scale` before use, so that they can be brought
+// into the same units as z, and the centre is reported in those units.
+pub struct Sphere {
+    pub scale: f64
+}
+
+// A cylinder whose axis lies along the unit vector (cos φ, sin φ) in the x-y
+// plane, offset from the origin by d along the normal (-sin φ, cos φ), and
+// rising along its length with the given slope. As for `Sphere`, the sign of
+// the radius says which way the surface bulges, and x and y are multiplied by
+// `scale` before use.
+pub struct Cylinder {
+    pub scale: f64
+}
+
+
+
+impl Sphere {
+    fn scaled(&self, x: f64, y: f64) -> (f64, f64) {
+        (x*self.scale, y*self.scale)
+    }
+}
+
+impl SurfaceModel for Sphere {
+    fn name(&self) -> String {
+        "sphere".to_string()
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        ["xc", "yc", "zc", "radius"].map(String::from).to_vec()
+    }
+
+    // An algebraic fit of x²+y²+z²+Dx+Ey+Fz+G = 0, made about the centroid
+    // for better conditioning, gives a starting point that is then refined to
+    // minimise the actual residuals.
+    fn fit(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Array1<f64> {
+        let scaled = scale_points(points, self.scale);
+        let total = weights.sum();
+        let centroid: Vec<f64> = (0..3).map(|i| scaled.column(i).dot(&weights)/total).collect();
+        let sqrt_w = weights.mapv(f64::sqrt);
+        let mut matrix = Array2::<f64>::ones((points.nrows(), 4)); // [[x, y, z, 1], ...]
+        let mut rhs = Array1::<f64>::zeros(points.nrows());
+
+        for (i, (p, w)) in scaled.rows().into_iter().zip(&sqrt_w).enumerate() {
+            let (x, y, z) = (p[0]-centroid[0], p[1]-centroid[1], p[2]-centroid[2]);
+
+            matrix.row_mut(i).assign(&array![w*x, w*y, w*z, *w]);
+            rhs[i] = -w*(x*x+y*y+z*z);
+        }
+
+        let s = matrix.least_squares(&rhs)
+            .expect("Could not find least squares fit for given points")
+            .solution;
+        let (xc, yc, zc) = (-s[0]/2., -s[1]/2., -s[2]/2.);
+        let radius = (xc*xc+yc*yc+zc*zc-s[3]).max(0.).sqrt();
+
+        // The points are centred on their mean z, so the cap bulges towards +z
+        // if the centre is below 0
+        let radius = if zc < 0. { radius } else { -radius };
+        let initial = array![xc+centroid[0], yc+centroid[1], zc+centroid[2], radius];
+
+        fit::refine_fit(self, points, weights, initial.view(), REFINE_ITERATIONS).coeffs
+    }
+
+    fn evaluate(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> f64 {
+        let (x, y) = self.scaled(x, y);
+        let r = coeffs[3];
+        let h = (r*r-(x-coeffs[0]).powi(2)-(y-coeffs[1]).powi(2)).max(0.).sqrt();
+
+        coeffs[2]+r.signum()*h
+    }
+
+    fn gradient(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> Array1<f64> {
+        let (x, y) = self.scaled(x, y);
+        let r = coeffs[3];
+        let h = (r*r-(x-coeffs[0]).powi(2)-(y-coeffs[1]).powi(2)).max(f64::EPSILON).sqrt();
+        let s = r.signum();
+
+        array![s*(x-coeffs[0])/h, s*(y-coeffs[1])/h, 1., r.abs()/h]
+    }
+
+    fn condition_number(
+        &self,
+        points: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        coeffs: ArrayView1<f64>
+    ) -> anyhow::Result<f64> {
+        model::condition_number(weighted_jacobian(self, points, weights, coeffs))
+    }
+}
+
+impl Cylinder {
+    // Coordinates across and along the axis.
+    fn axis_coords(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> (f64, f64) {
+        let (x, y) = (x*self.scale, y*self.scale);
+        let (sin, cos) = coeffs[0].sin_cos();
+
+        (-sin*x+cos*y-coeffs[1], cos*x+sin*y)
+    }
+}
+
+impl SurfaceModel for Cylinder {
+    fn name(&self) -> String {
+        "cylinder".to_string()
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        ["axis_angle", "axis_offset", "zc", "axis_slope", "radius"].map(String::from).to_vec()
+    }
+
+    // A quadratic fit gives the starting point. The axis runs along the
+    // direction of least curvature of the quadratic, the curvature across it
+    // gives the radius, and the axis sits where the quadratic is stationary
+    // across it. This is then refined to minimise the actual residuals.
+    fn fit(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Array1<f64> {
+        let scaled = scale_points(points, self.scale);
+        let q = Polynomial::new(2).fit(scaled.view(), weights); // [1, x, y, x², xy, y²]
+        let (hxx, hxy, hyy) = (2.*q[3], q[4], 2.*q[5]);
+
+        // The Hessian has eigenvalues mean ± diff, the first with eigenvector
+        // at angle theta and the second perpendicular to it
+        let mean = (hxx+hyy)/2.;
+        let diff = ((hxx-hyy)/2.).hypot(hxy);
+        let theta = (2.*hxy).atan2(hxx-hyy)/2.;
+        let (across, phi) = if (mean+diff).abs() >= (mean-diff).abs() {
+            (mean+diff, theta+std::f64::consts::FRAC_PI_2)
+        }
+        else {
+            (mean-diff, theta)
+        };
+        let (sin, cos) = phi.sin_cos();
+        let curvature = if across.abs() > f64::EPSILON { across } else { -f64::EPSILON };
+        let radius = -1./curvature;
+        let offset = -(-sin*q[1]+cos*q[2])/curvature;
+        let (x0, y0) = (-sin*offset, cos*offset);
+        let apex = q[0]+q[1]*x0+q[2]*y0+q[3]*x0*x0+q[4]*x0*y0+q[5]*y0*y0;
+        let slope = cos*q[1]+sin*q[2];
+        let initial = array![phi, offset, apex-radius, slope, radius];
+
+        fit::refine_fit(self, points, weights, initial.view(), REFINE_ITERATIONS).coeffs
+    }
+
+    fn evaluate(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> f64 {
+        let (u, v) = self.axis_coords(coeffs, x, y);
+        let r = coeffs[4];
+
+        coeffs[2]+coeffs[3]*v+r.signum()*(r*r-u*u).max(0.).sqrt()
+    }
+
+    fn gradient(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> Array1<f64> {
+        let (u, v) = self.axis_coords(coeffs, x, y);
+        let r = coeffs[4];
+        let h = (r*r-u*u).max(f64::EPSILON).sqrt();
+        let s = r.signum();
+
+        array![coeffs[3]*(u+coeffs[1])+s*u*v/h, s*u/h, 1., v, r.abs()/h]
+    }
+
+    fn condition_number(
+        &self,
+        points: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        coeffs: ArrayView1<f64>
+    ) -> anyhow::Result<f64> {
+        model::condition_number(weighted_jacobian(self, points, weights, coeffs))
+    }
+}
+
+
+
+// Copy of [x, y, z] for each of `points` with x and y multiplied by `scale`.
+fn scale_points(points: ArrayView2<f64>, scale: f64) -> Array2<f64> {
+    let mut scaled = points.slice(s![.., ..3]).to_owned();
+
+    scaled.slice_mut(s![.., ..2]).mapv_inplace(|v| v*scale);
+    scaled
+}
+
+// The primitives have no linear problem, so their conditioning is judged from
+// the Jacobian at the solution instead, with each row scaled by the square
+// root of its weight.
+fn weighted_jacobian(
+    model: &dyn SurfaceModel,
+    points: ArrayView2<f64>,
+    weights: ArrayView1<f64>,
+    coeffs: ArrayView1<f64>
+) -> Array2<f64> {
+    let mut jacobian = Array2::<f64>::zeros((points.nrows(), model.param_count()));
+
+    for ((p, mut row), w) in points.rows().into_iter().zip(jacobian.rows_mut()).zip(&weights) {
+        row.assign(&(model.gradient(coeffs, p[0], p[1])*w.sqrt()));
+    }
+
+    jacobian
+}
@@ -0,0 +1,100 @@
+use ndarray::prelude::*;
+
+
+
+// A region of interest in pixel coordinates, which the fit is restricted to.
+pub enum Roi {
+    // Inclusive bounds (x0, y0) to (x1, y1)
+    Rect(f64, f64, f64, f64),
+    Polygon(Vec<(f64, f64)>),
+    // True for pixels inside the region, indexed by [row, column]
+    Mask(Array2<bool>)
+}
+
+// Rectangle given on the command line as X0,Y0,X1,Y1.
+#[derive(Clone, Copy)]
+pub struct Rect(pub f64, pub f64, pub f64, pub f64);
+
+// Polygon given on the command line as X1,Y1,X2,Y2,X3,Y3,...
+#[derive(Clone)]
+pub struct Polygon(pub Vec<(f64, f64)>);
+
+
+
+impl std::str::FromStr for Rect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+
+        if parts.len() == 4 {
+            let v: Vec<f64> = parts.iter()
+                .map(|p| p.parse().map_err(|_| format!("Invalid float {p} in rectangle")))
+                .collect::<Result<_, _>>()?;
+
+            Ok(Self(v[0].min(v[2]), v[1].min(v[3]), v[0].max(v[2]), v[1].max(v[3])))
+        }
+        else {
+            Err("Rectangle must be of the form X0,Y0,X1,Y1".to_string())
+        }
+    }
+}
+
+impl std::str::FromStr for Polygon {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+
+        if parts.len() >= 6 && parts.len().is_multiple_of(2) {
+            let v: Vec<f64> = parts.iter()
+                .map(|p| p.parse().map_err(|_| format!("Invalid float {p} in polygon")))
+                .collect::<Result<_, _>>()?;
+
+            Ok(Self(v.chunks(2).map(|c| (c[0], c[1])).collect()))
+        }
+        else {
+            Err("Polygon must be of the form X1,Y1,X2,Y2,X3,Y3,... with at least 3 vertices".to_string())
+        }
+    }
+}
+
+impl Roi {
+    pub fn from_rect(rect: Rect) -> Self {
+        Self::Rect(rect.0, rect.1, rect.2, rect.3)
+    }
+
+    pub fn from_polygon(polygon: Polygon) -> Self {
+        Self::Polygon(polygon.0)
+    }
+
+    pub fn from_mask(mask: Array2<bool>) -> Self {
+        Self::Mask(mask)
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        match self {
+            &Self::Rect(x0, y0, x1, y1) => x >= x0 && x <= x1 && y >= y0 && y <= y1,
+            Self::Polygon(vertices) => {
+                // Even-odd rule: count crossings of a ray from (x, y) towards
+                // +x
+                let mut inside = false;
+
+                for (i, &(xi, yi)) in vertices.iter().enumerate() {
+                    let (xj, yj) = vertices[(i+1)%vertices.len()];
+
+                    if (yi > y) != (yj > y) && x < xi+(y-yi)*(xj-xi)/(yj-yi) {
+                        inside = !inside;
+                    }
+                }
+
+                inside
+            },
+            Self::Mask(mask) => {
+                let (i, j) = (y.round(), x.round());
+
+                i >= 0. && j >= 0. && mask.get((i as usize, j as usize)).copied().unwrap_or(false)
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::f64::consts::{PI, TAU};
+use ndarray::prelude::*;
+use ndarray::Zip;
+
+
+
+// Ways of combining wrapped phases at several fringe frequencies.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Scheme {
+    /// Each phase is unwrapped from the one at the next lowest frequency,
+    /// starting from one with at most a single fringe across the field
+    Hierarchical,
+    /// Beat phases at the differences between adjacent frequencies are taken,
+    /// and beats of those in turn, until one has at most a single fringe
+    /// across the field, e.g. 64, 63 and 56 fringes beat to 1 and 7. The
+    /// phases are then unwrapped back up hierarchically
+    Heterodyne
+}
+
+
+
+// Unwrap the highest frequency phase of `stack`, indexed by [phase, row,
+// column], with the phase at index k having frequencies[k] fringes across the
+// field. Each pixel is unwrapped on its own. The quality is 1 minus the
+// largest disagreement between the phase predicted from a lower frequency and
+// the unwrapped phase at each step, as a fraction of π, beyond which a pixel
+// is unwrapped to the wrong fringe, so it falls to 0 as unwrapping fails.
+pub fn unwrap(scheme: Scheme, stack: ArrayView3<f64>, frequencies: &[f64]) -> anyhow::Result<(Array2<f64>, Array2<f64>)> {
+    let n = stack.len_of(Axis(0));
+
+    anyhow::ensure!(n == frequencies.len(), "{n} wrapped phases were given, but {} frequencies", frequencies.len());
+    anyhow::ensure!(n >= 2, "Temporal unwrapping needs phases at 2 or more frequencies");
+
+    let mut levels: Vec<(f64, Array2<f64>)> = frequencies.iter().copied().zip(stack.outer_iter().map(|p| p.to_owned())).collect();
+
+    levels.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    anyhow::ensure!(levels.iter().all(|&(f, _)| f > 0.), "Fringe frequencies must be positive");
+    anyhow::ensure!(levels.windows(2).all(|l| l[0].0 != l[1].0), "Fringe frequencies must all be different");
+
+    let mut error = Array2::<f64>::zeros(levels[0].1.dim());
+    let unwrapped = match scheme {
+        Scheme::Hierarchical => {
+            let lowest = levels[n-1].0;
+
+            anyhow::ensure!(
+                lowest <= 1.,
+                "The lowest fringe frequency is {lowest}, but must be at most 1 for its phase to be unambiguous"
+            );
+
+            hierarchical(levels, &mut error).1
+        },
+        Scheme::Heterodyne => heterodyne(levels, &mut error)?.1
+    };
+
+    Ok((unwrapped, error.mapv(|e| 1.-e/PI)))
+}
+
+
+
+// Unwrap the first of `levels`, which are in decreasing order of frequency,
+// from the last, which must have at most one fringe, through each in between.
+// Returns the frequency of the first and its unwrapped phase.
+fn hierarchical(mut levels: Vec<(f64, Array2<f64>)>, error: &mut Array2<f64>) -> (f64, Array2<f64>) {
+    let (mut frequency, base) = levels.pop().unwrap();
+    let mut unwrapped = base.mapv(|p| p.rem_euclid(TAU));
+
+    while let Some((next, wrapped)) = levels.pop() {
+        unwrapped = step(frequency, &unwrapped, next, &wrapped, error);
+        frequency = next;
+    }
+
+    (frequency, unwrapped)
+}
+
+// Unwrap the first of `levels`, which are in decreasing order of frequency,
+// returning its frequency and unwrapped phase. Beats are only taken until
+// some level has at most one fringe.
+fn heterodyne(levels: Vec<(f64, Array2<f64>)>, error: &mut Array2<f64>) -> anyhow::Result<(f64, Array2<f64>)> {
+    let lowest = levels[levels.len()-1].0;
+
+    if lowest <= 1. {
+        return Ok(hierarchical(levels, error));
+    }
+
+    anyhow::ensure!(
+        levels.len() > 1,
+        "The fringe frequencies beat down to {lowest}, but must reach at most 1 for the phase to be unambiguous"
+    );
+
+    let mut beats: Vec<(f64, Array2<f64>)> = levels.windows(2)
+        .map(|l| (l[0].0-l[1].0, (&l[0].1-&l[1].1).mapv(|d| d.rem_euclid(TAU))))
+        .collect();
+
+    // Beats at the same frequency carry the same information, e.g. from
+    // equally spaced frequencies
+    beats.sort_by(|a, b| b.0.total_cmp(&a.0));
+    beats.dedup_by(|a, b| a.0 == b.0);
+
+    let (beat_frequency, beat) = heterodyne(beats, error)?;
+    let (frequency, wrapped) = &levels[0];
+
+    Ok((*frequency, step(beat_frequency, &beat, *frequency, wrapped, error)))
+}
+
+// Unwrap `wrapped`, at `frequency`, to the fringe nearest that predicted by
+// scaling up `unwrapped`, at the lower `from_frequency`, recording the largest
+// disagreement so far in `error`.
+fn step(
+    from_frequency: f64,
+    unwrapped: &Array2<f64>,
+    frequency: f64,
+    wrapped: &Array2<f64>,
+    error: &mut Array2<f64>
+) -> Array2<f64> {
+    let ratio = frequency/from_frequency;
+
+    Zip::from(unwrapped).and(wrapped).and(error)
+        .map_collect(|&u, &w, e| {
+            let predicted = u*ratio;
+            let result = w+TAU*((predicted-w)/TAU).round();
+
+            *e = e.max((predicted-result).abs());
+            result
+        })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heterodyne_unwraps_ramp() {
+        let frequencies = [64., 63., 56.];
+        let ramp = Array1::linspace(0., 0.999, 200);
+        let stack = Array3::from_shape_fn((3, 1, ramp.len()), |(k, _, j)| {
+            (TAU*frequencies[k]*ramp[j]+PI).rem_euclid(TAU)-PI
+        });
+
+        let (unwrapped, quality) = unwrap(Scheme::Heterodyne, stack.view(), &frequencies).unwrap();
+
+        for (&u, &r) in unwrapped.iter().zip(&ramp) {
+            assert!((u-TAU*64.*r).abs() < 1e-9, "unwrapped {u}, expected {}", TAU*64.*r);
+        }
+
+        assert!(quality.iter().all(|&q| q > 0.99));
+    }
+}
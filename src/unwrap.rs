@@ -0,0 +1,305 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::f64::consts::{PI, TAU};
+use ndarray::prelude::*;
+use ndarray::Zip;
+
+
+
+// Algorithms for unwrapping a wrapped phase map.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Method {
+    /// Flood fill from the best pixel, always unwrapping the best quality
+    /// neighbour of the region unwrapped so far next
+    QualityGuided,
+    /// Goldstein's branch cuts between residues, then flood fill around them
+    BranchCut,
+    /// Unweighted least squares fit to the wrapped gradients, solved with the
+    /// discrete cosine transform
+    LeastSquares
+}
+
+// A pixel waiting to be unwrapped from its already unwrapped neighbour,
+// ordered by quality so that the heap yields the best first.
+struct Edge {
+    quality: f64,
+    pixel: (usize, usize),
+    from: (usize, usize)
+}
+
+
+
+impl PartialEq for Edge {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Edge {}
+
+impl PartialOrd for Edge {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Edge {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.quality.total_cmp(&other.quality)
+    }
+}
+
+
+
+// Unwrap `wrapped` over the pixels where `valid` is true, returning NaN
+// elsewhere and wherever the method could not reach. Each connected region is
+// unwrapped independently, so may be offset from the others by a multiple of
+// 2π.
+pub fn unwrap(method: Method, wrapped: &Array2<f64>, quality: &Array2<f64>, valid: &Array2<bool>) -> Array2<f64> {
+    match method {
+        Method::QualityGuided => quality_guided(wrapped, quality, valid),
+        Method::BranchCut => branch_cut(wrapped, valid),
+        Method::LeastSquares => least_squares(wrapped, valid)
+    }
+}
+
+// Quality guided flood fill, which keeps errors from unreliable pixels out of
+// the rest of the map by unwrapping them last.
+fn quality_guided(wrapped: &Array2<f64>, quality: &Array2<f64>, valid: &Array2<bool>) -> Array2<f64> {
+    let mut unwrapped = Array2::from_elem(wrapped.dim(), f64::NAN);
+    let mut heap = BinaryHeap::new();
+    let mut seeds: Vec<(usize, usize)> = valid.indexed_iter().filter(|(_, &v)| v).map(|(p, _)| p).collect();
+
+    seeds.sort_by(|&a, &b| quality[b].total_cmp(&quality[a]));
+
+    for seed in seeds {
+        if !unwrapped[seed].is_nan() {
+            continue;
+        }
+
+        unwrapped[seed] = wrapped[seed];
+        heap.extend(neighbours(seed, wrapped.dim()).map(|p| Edge { quality: quality[p], pixel: p, from: seed }));
+
+        while let Some(Edge { pixel, from, .. }) = heap.pop() {
+            if !valid[pixel] || !unwrapped[pixel].is_nan() {
+                continue;
+            }
+
+            unwrapped[pixel] = unwrapped[from]+wrap(wrapped[pixel]-wrapped[from]);
+            heap.extend(neighbours(pixel, wrapped.dim()).map(|p| Edge { quality: quality[p], pixel: p, from: pixel }));
+        }
+    }
+
+    unwrapped
+}
+
+// Goldstein's branch cut method. Residues, where the wrapped gradient around
+// a 2x2 loop does not sum to zero, are joined by cuts into groups of zero net
+// charge, or to the border, and the map is then flood filled without crossing
+// any cut, so that the result does not depend on the path taken.
+fn branch_cut(wrapped: &Array2<f64>, valid: &Array2<bool>) -> Array2<f64> {
+    let (h, w) = wrapped.dim();
+    let mut charge = Array2::<i32>::zeros((h, w));
+
+    // A residue is recorded at the top-left pixel of its loop
+    for i in 0..h.saturating_sub(1) {
+        for j in 0..w.saturating_sub(1) {
+            let corners = [(i, j), (i, j+1), (i+1, j+1), (i+1, j)];
+
+            if corners.iter().all(|&p| valid[p]) {
+                let sum: f64 = (0..4).map(|k| wrap(wrapped[corners[(k+1)%4]]-wrapped[corners[k]])).sum();
+
+                charge[[i, j]] = (sum/TAU).round() as i32;
+            }
+        }
+    }
+
+    let cuts = place_cuts(&charge, valid);
+    let mut unwrapped = Array2::from_elem((h, w), f64::NAN);
+    let mut queue = VecDeque::new();
+
+    // Flood fill each region bounded by cuts, then unwrap the cut pixels from
+    // any unwrapped neighbour
+    for seed in (0..h).flat_map(|i| (0..w).map(move |j| (i, j))) {
+        if !valid[seed] || cuts[seed] || !unwrapped[seed].is_nan() {
+            continue;
+        }
+
+        unwrapped[seed] = wrapped[seed];
+        queue.push_back(seed);
+
+        while let Some(from) = queue.pop_front() {
+            for p in neighbours(from, (h, w)) {
+                if valid[p] && !cuts[p] && unwrapped[p].is_nan() {
+                    unwrapped[p] = unwrapped[from]+wrap(wrapped[p]-wrapped[from]);
+                    queue.push_back(p);
+                }
+            }
+        }
+    }
+
+    for pixel in (0..h).flat_map(|i| (0..w).map(move |j| (i, j))) {
+        if valid[pixel] && cuts[pixel] {
+            let from = neighbours(pixel, (h, w)).find(|&p| !cuts[p] && !unwrapped[p].is_nan());
+
+            if let Some(from) = from {
+                unwrapped[pixel] = unwrapped[from]+wrap(wrapped[pixel]-wrapped[from]);
+            }
+        }
+    }
+
+    unwrapped
+}
+
+// Join residues with cuts. From each unbalanced residue, a box is grown and
+// every residue found in it is joined to the group, until the group's charge
+// is balanced or the border (the image edge or an invalid pixel) is reached.
+fn place_cuts(charge: &Array2<i32>, valid: &Array2<bool>) -> Array2<bool> {
+    let (h, w) = charge.dim();
+    let mut cuts = Array2::from_elem((h, w), false);
+    let mut grouped = Array2::from_elem((h, w), false);
+    let is_border = |i: isize, j: isize| {
+        i < 0 || j < 0 || i >= h as isize || j >= w as isize || !valid[[i as usize, j as usize]]
+    };
+
+    for start in (0..h).flat_map(|i| (0..w).map(move |j| (i, j))) {
+        if charge[start] == 0 || grouped[start] {
+            continue;
+        }
+
+        let mut group = vec![start];
+        let mut total = charge[start];
+
+        grouped[start] = true;
+        cuts[start] = true;
+
+        'grow: for radius in 1..h.max(w) as isize {
+            let mut k = 0;
+
+            while k < group.len() {
+                let (ci, cj) = group[k];
+
+                for i in ci as isize-radius..=ci as isize+radius {
+                    for j in cj as isize-radius..=cj as isize+radius {
+                        if is_border(i, j) {
+                            draw_cut(&mut cuts, group[k], (i, j));
+                            break 'grow;
+                        }
+
+                        let p = (i as usize, j as usize);
+
+                        if charge[p] != 0 && !grouped[p] {
+                            grouped[p] = true;
+                            group.push(p);
+                            total += charge[p];
+                            draw_cut(&mut cuts, (ci, cj), (i, j));
+
+                            if total == 0 {
+                                break 'grow;
+                            }
+                        }
+                    }
+                }
+
+                k += 1;
+            }
+        }
+    }
+
+    cuts
+}
+
+// Mark the pixels on the line from `a` to `b` as cut, clipped to the image.
+fn draw_cut(cuts: &mut Array2<bool>, a: (usize, usize), b: (isize, isize)) {
+    let (h, w) = cuts.dim();
+    let (di, dj) = (b.0-a.0 as isize, b.1-a.1 as isize);
+    let steps = di.abs().max(dj.abs()).max(1);
+
+    for s in 0..=steps {
+        let t = s as f64/steps as f64;
+        let i = (a.0 as f64+di as f64*t).round() as isize;
+        let j = (a.1 as f64+dj as f64*t).round() as isize;
+
+        if i >= 0 && j >= 0 && (i as usize) < h && (j as usize) < w {
+            cuts[[i as usize, j as usize]] = true;
+        }
+    }
+}
+
+// Least squares unwrapping (Ghiglia and Romero), which solves the Poisson
+// equation relating the unwrapped phase to the divergence of the wrapped
+// gradients with Neumann boundaries. Gradients to invalid pixels are taken to
+// be zero. The solution is smooth rather than congruent to the wrapped phase,
+// so it is finally snapped to the nearest congruent value.
+fn least_squares(wrapped: &Array2<f64>, valid: &Array2<bool>) -> Array2<f64> {
+    let (h, w) = wrapped.dim();
+    let mut dx = Array2::<f64>::zeros((h, w));
+    let mut dy = Array2::<f64>::zeros((h, w));
+
+    for i in 0..h {
+        for j in 0..w {
+            if j+1 < w && valid[[i, j]] && valid[[i, j+1]] {
+                dx[[i, j]] = wrap(wrapped[[i, j+1]]-wrapped[[i, j]]);
+            }
+
+            if i+1 < h && valid[[i, j]] && valid[[i+1, j]] {
+                dy[[i, j]] = wrap(wrapped[[i+1, j]]-wrapped[[i, j]]);
+            }
+        }
+    }
+
+    let mut rho = Array2::<f64>::zeros((h, w));
+
+    for i in 0..h {
+        for j in 0..w {
+            rho[[i, j]] = dx[[i, j]]+dy[[i, j]]
+                -if j > 0 { dx[[i, j-1]] } else { 0. }
+                -if i > 0 { dy[[i-1, j]] } else { 0. };
+        }
+    }
+
+    // In the DCT basis the discrete Laplacian is diagonal
+    let (ch, cw) = (dct_matrix(h), dct_matrix(w));
+    let mut spectrum = ch.dot(&rho).dot(&cw.t());
+
+    for ((k, l), v) in spectrum.indexed_iter_mut() {
+        let eigenvalue = 2.*(PI*k as f64/h as f64).cos()+2.*(PI*l as f64/w as f64).cos()-4.;
+
+        *v = if k == 0 && l == 0 { 0. } else { *v/eigenvalue };
+    }
+
+    let solution = idct_matrix(h).dot(&spectrum).dot(&idct_matrix(w).t());
+
+    Zip::from(&solution).and(wrapped).and(valid)
+        .map_collect(|&s, &p, &v| if v { s+wrap(p-s) } else { f64::NAN })
+}
+
+
+
+// Wrap a phase difference into [-π, π].
+fn wrap(phase: f64) -> f64 {
+    phase-TAU*(phase/TAU).round()
+}
+
+// The 4-connected neighbours of `pixel` within an image of size `dim`.
+fn neighbours(pixel: (usize, usize), dim: (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+    let (i, j) = pixel;
+
+    [(i.wrapping_sub(1), j), (i+1, j), (i, j.wrapping_sub(1)), (i, j+1)]
+        .into_iter()
+        .filter(move |&(i, j)| i < dim.0 && j < dim.1)
+}
+
+// Matrix of the (unnormalised) DCT-II of length n.
+fn dct_matrix(n: usize) -> Array2<f64> {
+    Array2::from_shape_fn((n, n), |(k, m)| (PI*k as f64*(2.*m as f64+1.)/(2.*n as f64)).cos())
+}
+
+// Matrix of the inverse of `dct_matrix(n)`, a scaled DCT-III.
+fn idct_matrix(n: usize) -> Array2<f64> {
+    Array2::from_shape_fn((n, n), |(m, k)| {
+        let scale = if k == 0 { 1. } else { 2. }/n as f64;
+
+        scale*(PI*k as f64*(2.*m as f64+1.)/(2.*n as f64)).cos()
+    })
+}
@@ -0,0 +1,249 @@
+use ndarray::prelude::*;
+use ndarray_linalg::LeastSquaresSvd;
+use crate::model::{self, SurfaceModel};
+
+
+
+// How Zernike terms are numbered when reported or selected.
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Indexing {
+    /// Noll's single index, starting from Z1 = piston
+    Noll,
+    /// The OSA/ANSI single index, starting from Z0 = piston
+    Osa
+}
+
+// A circular aperture, in the same lateral units as the points it selects.
+#[derive(Clone, Copy)]
+pub struct Aperture {
+    pub cx: f64,
+    pub cy: f64,
+    pub radius: f64
+}
+
+// Zernike polynomials up to a given radial order over a circular aperture.
+// Terms are normalised so that each coefficient is the RMS of its term over
+// the aperture. Points outside the aperture should be excluded before fitting.
+pub struct Zernike {
+    aperture: Aperture,
+    indexing: Indexing,
+    terms: Vec<(i32, i32)> // (n, m), in OSA order
+}
+
+
+
+impl std::str::FromStr for Aperture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+
+        if parts.len() == 3 {
+            let cx: f64 = parts[0].parse().map_err(|_| "Invalid float for aperture centre x")?;
+            let cy: f64 = parts[1].parse().map_err(|_| "Invalid float for aperture centre y")?;
+            let radius: f64 = parts[2].parse().map_err(|_| "Invalid float for aperture radius")?;
+
+            if radius > 0. {
+                Ok(Self { cx, cy, radius })
+            }
+            else {
+                Err("Aperture radius must be positive".to_string())
+            }
+        }
+        else {
+            Err("Aperture must be of the form CX,CY,RADIUS".to_string())
+        }
+    }
+}
+
+impl Aperture {
+    // Estimate the aperture from the points inside it, assuming they fill it.
+    // The centre is their centroid, and as the mean squared distance from the
+    // centre of a filled disk is half its squared radius, the radius is
+    // sqrt(2 mean r²). This is far less sensitive to stray points than the
+    // distance to the furthest one, and doesn't depend on the point spacing.
+    pub fn fit(points: ArrayView2<f64>) -> Self {
+        let cx = points.column(0).mean().unwrap();
+        let cy = points.column(1).mean().unwrap();
+        let mean_r2 = points.rows().into_iter()
+            .map(|p| (p[0]-cx).powi(2)+(p[1]-cy).powi(2))
+            .sum::<f64>()/points.nrows() as f64;
+
+        Self { cx, cy, radius: (2.*mean_r2).sqrt() }
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        (x-self.cx).hypot(y-self.cy) <= self.radius
+    }
+}
+
+impl Zernike {
+    pub fn new(order: u32, aperture: Aperture, indexing: Indexing) -> Self {
+        let order = order as i32;
+        let terms = (0..=order)
+            .flat_map(|n| (-n..=n).step_by(2).map(move |m| (n, m)))
+            .collect();
+
+        Self { aperture, indexing, terms }
+    }
+
+    // Value of each term at (x, y).
+    fn basis(&self, x: f64, y: f64) -> Array1<f64> {
+        let dx = (x-self.aperture.cx)/self.aperture.radius;
+        let dy = (y-self.aperture.cy)/self.aperture.radius;
+        let (rho, theta) = (dx.hypot(dy), dy.atan2(dx));
+
+        self.terms.iter()
+            .map(|&(n, m)| {
+                let norm = if m == 0 { (n as f64+1.).sqrt() } else { (2.*(n as f64+1.)).sqrt() };
+                let angular = if m >= 0 { (m as f64*theta).cos() } else { (-m as f64*theta).sin() };
+
+                norm*radial(n, m.abs(), rho)*angular
+            })
+            .collect()
+    }
+
+    fn design_matrix(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> (Array2<f64>, Array1<f64>) {
+        let sqrt_w = weights.mapv(f64::sqrt);
+        let mut matrix = Array2::<f64>::zeros((points.nrows(), self.terms.len()));
+
+        for ((p, mut row), w) in points.rows().into_iter().zip(matrix.rows_mut()).zip(&sqrt_w) {
+            row.assign(&(self.basis(p[0], p[1])**w));
+        }
+
+        (matrix, &points.column(2)*&sqrt_w)
+    }
+
+    fn index(&self, n: i32, m: i32) -> i32 {
+        match self.indexing {
+            Indexing::Osa => (n*(n+2)+m)/2,
+            Indexing::Noll => {
+                // Within each radial order, Noll indices increase with |m|,
+                // and of each pair the even index is the cosine (m > 0) term
+                let mut j = n*(n+1)/2+1;
+
+                for am in (n%2..m.abs()).step_by(2) {
+                    j += if am == 0 { 1 } else { 2 };
+                }
+
+                if m != 0 && (j%2 == 0) != (m > 0) {
+                    j += 1;
+                }
+
+                j
+            }
+        }
+    }
+}
+
+impl SurfaceModel for Zernike {
+    fn name(&self) -> String {
+        let order = self.terms.last().map_or(0, |&(n, _)| n);
+        let Aperture { cx, cy, radius } = self.aperture;
+
+        format!("Zernike polynomials to order {order} over the aperture at ({cx:.1}, {cy:.1}) with radius {radius:.1}")
+    }
+
+    fn param_names(&self) -> Vec<String> {
+        self.terms.iter().map(|&(n, m)| format!("Z{}", self.index(n, m))).collect()
+    }
+
+    fn param_labels(&self) -> Vec<String> {
+        self.terms.iter()
+            .map(|&(n, m)| match term_name(n, m) {
+                Some(name) => format!("Z{} ({name})", self.index(n, m)),
+                None => format!("Z{}", self.index(n, m))
+            })
+            .collect()
+    }
+
+    fn fit(&self, points: ArrayView2<f64>, weights: ArrayView1<f64>) -> Array1<f64> {
+        let (matrix, z) = self.design_matrix(points, weights);
+
+        matrix.least_squares(&z)
+            .expect("Could not find least squares fit for given points")
+            .solution
+    }
+
+    fn evaluate(&self, coeffs: ArrayView1<f64>, x: f64, y: f64) -> f64 {
+        self.basis(x, y).dot(&coeffs)
+    }
+
+    fn gradient(&self, _coeffs: ArrayView1<f64>, x: f64, y: f64) -> Array1<f64> {
+        self.basis(x, y)
+    }
+
+    // Z(0, 0) is 1 everywhere, so its coefficient is the piston itself
+    fn piston(&self, coeffs: ArrayView1<f64>) -> Option<f64> {
+        self.terms.iter().position(|&t| t == (0, 0)).map(|i| coeffs[i])
+    }
+
+    fn condition_number(
+        &self,
+        points: ArrayView2<f64>,
+        weights: ArrayView1<f64>,
+        _coeffs: ArrayView1<f64>
+    ) -> anyhow::Result<f64> {
+        let (matrix, _) = self.design_matrix(points, weights);
+
+        model::condition_number(matrix)
+    }
+}
+
+
+
+// Radial polynomial R_n^m(rho) for m >= 0 and n-m even.
+fn radial(n: i32, m: i32, rho: f64) -> f64 {
+    (0..=(n-m)/2)
+        .map(|k| {
+            let sign = if k%2 == 0 { 1. } else { -1. };
+            let c = factorial(n-k)/(factorial(k)*factorial((n+m)/2-k)*factorial((n-m)/2-k));
+
+            sign*c*rho.powi(n-2*k)
+        })
+        .sum()
+}
+
+fn factorial(n: i32) -> f64 {
+    (1..=n).map(|i| i as f64).product()
+}
+
+// Conventional names of the low order aberrations.
+fn term_name(n: i32, m: i32) -> Option<&'static str> {
+    Some(match (n, m) {
+        (0, 0) => "piston",
+        (1, 1) => "tilt x",
+        (1, -1) => "tilt y",
+        (2, 0) => "defocus",
+        (2, 2) => "vertical astigmatism",
+        (2, -2) => "oblique astigmatism",
+        (3, 1) => "horizontal coma",
+        (3, -1) => "vertical coma",
+        (3, 3) => "oblique trefoil",
+        (3, -3) => "vertical trefoil",
+        (4, 0) => "primary spherical",
+        _ => return None
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noll_indices() {
+        // Noll (1976), table 1
+        let table = [
+            (0, 0), (1, 1), (1, -1), (2, 0), (2, -2), (2, 2), (3, -1), (3, 1),
+            (3, -3), (3, 3), (4, 0), (4, 2), (4, -2), (4, 4), (4, -4)
+        ];
+        let aperture = Aperture { cx: 0., cy: 0., radius: 1. };
+        let zernike = Zernike::new(4, aperture, Indexing::Noll);
+
+        for (j, &(n, m)) in (1..).zip(&table) {
+            assert_eq!(zernike.index(n, m), j, "Z(n = {n}, m = {m})");
+        }
+    }
+}